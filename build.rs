@@ -0,0 +1,125 @@
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const MOONEYE_DIR: &str = "roms/mooneye";
+
+// Directories holding roms that don't report through the register signature
+const SKIPPED_DIRS: [&str; 3] = ["manual-only", "utils", "madness"];
+
+// Model tags mooneye roms end in, longest first so `sgb2` isn't read as `sgb`.
+// G is DMG and MGB, S is SGB and SGB2, C is CGB and A is AGB.
+const MODEL_TAGS: [(&str, &str); 14] = [
+    ("cgbABCDE", "CGB"),
+    ("dmgABC", "DMG"),
+    ("dmg0", "DMG0"),
+    ("sgb2", "SGB2"),
+    ("cgb0", "CGB"),
+    ("mgb", "MGB"),
+    ("sgb", "SGB"),
+    ("cgb", "CGB"),
+    ("agb", "AGB"),
+    ("ags", "AGB"),
+    ("G", "DMG"),
+    ("S", "SGB"),
+    ("C", "CGB"),
+    ("A", "AGB"),
+];
+
+/// The models named by the suffix after the last dash, `None` when there is
+/// no suffix or it isn't a list of model tags
+fn parse_models(name: &str) -> Option<Vec<&'static str>> {
+    let mut suffix = &name[name.rfind('-')? + 1..];
+    let mut models = Vec::new();
+    while !suffix.is_empty() {
+        let &(tag, model) = MODEL_TAGS.iter().find(|(tag, _)| suffix.starts_with(tag))?;
+        models.push(model);
+        suffix = &suffix[tag.len()..];
+    }
+    Some(models)
+}
+
+/// The model to run a rom on: DMG for roms without model tags and the ones
+/// that pass on it, else the first model they name.
+fn rom_model(name: &str) -> &'static str {
+    match parse_models(name) {
+        Some(models) if !models.contains(&"DMG") => models[0],
+        _ => "DMG",
+    }
+}
+
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap().flatten() {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if path.is_dir() {
+            if !SKIPPED_DIRS.contains(&name.as_str()) {
+                find_roms(&path, roms);
+            }
+        } else if name.ends_with(".gb") {
+            roms.push(path);
+        }
+    }
+}
+
+fn test_name(rom: &Path) -> String {
+    let name: String = rom
+        .with_extension("")
+        .strip_prefix("roms")
+        .unwrap()
+        .to_string_lossy()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    name.trim_matches('_').to_string()
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed={}", MOONEYE_DIR);
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let mut out = fs::File::create(out_dir.join("mooneye_tests.rs")).unwrap();
+    // without the roms a single ignored test stands in for the suite, so
+    // the rest of the tests still build and run
+    if !Path::new(MOONEYE_DIR).is_dir() {
+        println!(
+            "cargo:warning={} is missing, the mooneye tests are skipped",
+            MOONEYE_DIR
+        );
+        writeln!(
+            out,
+            "#[test]\n#[ignore = \"{0} is missing, copy the mooneye test suite roms there\"]\n\
+             fn mooneye_roms_missing() {{\n    mooneye_test(\"{0}/acceptance/boot_regs-dmgABC.gb\", Model::DMG);\n}}",
+            MOONEYE_DIR
+        )
+        .unwrap();
+        return;
+    }
+
+    let mut roms = Vec::new();
+    find_roms(Path::new(MOONEYE_DIR), &mut roms);
+    roms.sort();
+    assert!(!roms.is_empty(), "no roms found in {}", MOONEYE_DIR);
+
+    for rom in roms {
+        if let Some(dir) = rom.parent() {
+            println!("cargo:rerun-if-changed={}", dir.display());
+        }
+        writeln!(
+            out,
+            "#[test]\nfn {}() {{\n    mooneye_test({:?}, Model::{});\n}}\n",
+            test_name(&rom),
+            rom.to_string_lossy(),
+            rom_model(&rom.file_stem().unwrap().to_string_lossy())
+        )
+        .unwrap();
+    }
+}
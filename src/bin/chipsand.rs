@@ -1,68 +1,884 @@
 use std::fs;
-use std::path::PathBuf;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
-use std::sync::mpsc::TryRecvError;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
 use std::{thread, time};
 
+use sdl2::event::{Event, WindowEvent};
 use structopt::StructOpt;
 
-use chipsandlib::{cpu, save_screen_buffer};
-use chipsandlib::display::Display;
-use chipsandlib::input::{from_sdl2_event, Control};
+use anyhow::{anyhow, bail, Context, Result};
+use chipsandlib::cheats::{Cheat, Cheats};
+use chipsandlib::display::{Display, VramWindow};
+use chipsandlib::filter::Filter;
+use chipsandlib::input::{Bindings, BindingsConfig, Control, Controllers, Hotkey};
+use chipsandlib::link::TcpPeer;
+use chipsandlib::memory::{Comparison, RamSearch, Width};
 use chipsandlib::mmu::MMU;
-use anyhow::{Context, Result};
+use chipsandlib::model::Model;
+use chipsandlib::movie::Movie;
+use chipsandlib::pacing::Pacer;
+use chipsandlib::palette::{self, Palette};
+use chipsandlib::printer::Printer;
+use chipsandlib::recorder::Recorder;
+use chipsandlib::sgb::{Sgb, SGB_HEIGHT, SGB_WIDTH};
+use chipsandlib::vram::VramSnapshot;
+use chipsandlib::{
+    cpu, load_screen_buffer, save_screen_png, ScreenBuffer, BLANK_SCREEN, FRAME_CYCLES,
+};
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "chipsand")]
 struct Opt {
     #[structopt(name = "ROM", parse(from_os_str))]
-    rom: PathBuf,
+    rom: Option<PathBuf>,
+    #[structopt(subcommand)]
+    tool: Option<Tool>,
+    /// Window size as a multiple of 160x144
+    #[structopt(long, default_value = "4")]
+    scale: u32,
+    /// Upscaling filter: nearest, scale2x or lcd
+    #[structopt(long, default_value = "nearest")]
+    filter: Filter,
+    /// Blend every frame with the previous one like the slow original lcd
+    #[structopt(long)]
+    ghosting: bool,
+    /// Colours for the four shades: green, grey, pocket or light. Defaults to
+    /// green, or to the colours the CGB boot rom picks for the game with --model cgb
+    #[structopt(long)]
+    palette: Option<Palette>,
+    /// Palette with separate background and object colours, see `Palette::load`
+    #[structopt(long, parse(from_os_str), conflicts_with = "palette")]
+    palette_file: Option<PathBuf>,
+    /// Hardware to emulate: dmg0, dmg, mgb, sgb, sgb2, cgb or agb
+    #[structopt(long, default_value = "dmg")]
+    model: Model,
+    /// Run this DMG/CGB boot rom before the cartridge instead of skipping the boot sequence
+    #[structopt(long, parse(from_os_str))]
+    boot_rom: Option<PathBuf>,
+    /// Wait for a link cable connection on this address, e.g. 127.0.0.1:8765
+    #[structopt(long)]
+    link_listen: Option<String>,
+    /// Connect the link cable to an instance listening on this address
+    #[structopt(long, conflicts_with = "link-listen")]
+    link_connect: Option<String>,
+    /// Attach a Game Boy Printer to the link port, prints are saved as png into this directory
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["link-listen", "link-connect"])]
+    printer: Option<PathBuf>,
+    /// Key and controller bindings as toml, or json with a .json extension
+    #[structopt(long, parse(from_os_str))]
+    bindings: Option<PathBuf>,
+    /// Clock cycles after the start of VBlank at which input reaches the game each frame
+    #[structopt(long, default_value = "0")]
+    input_latch_cycle: u32,
+    /// Ignore left+right and up+down pressed together
+    #[structopt(long)]
+    filter_opposite_directions: bool,
+    /// Speed while fast-forward is held, 0 runs as fast as possible
+    #[structopt(long, default_value = "4")]
+    fast_forward: f64,
+    /// Speed while slow motion is held
+    #[structopt(long, default_value = "0.5")]
+    slow_motion: f64,
+    /// Directory screenshots, recordings and save states are written to
+    #[structopt(long, parse(from_os_str), default_value = ".")]
+    save_dir: PathBuf,
+    /// Start with the window covering the whole screen
+    #[structopt(long)]
+    fullscreen: bool,
+    /// Open the vram viewer window at startup, F2 toggles it
+    #[structopt(long)]
+    vram_viewer: bool,
+    /// Cheat codes as toml, defaults to <rom>.cheats.toml next to the rom when there is one
+    #[structopt(long, parse(from_os_str))]
+    cheats: Option<PathBuf>,
+    /// Read commands for inspecting memory and searching ram from stdin, type help for a list
+    #[structopt(long)]
+    console: bool,
+    /// Start with emulation paused, e.g. to frame advance from the first frame
+    #[structopt(long)]
+    paused: bool,
+    /// Continue from a save state written with the save state hotkey
+    #[structopt(long, parse(from_os_str))]
+    load_state: Option<PathBuf>,
+    /// Keep quiet. There is no sound emulation yet, so for now this changes nothing
+    #[structopt(long)]
+    mute: bool,
+    /// Run this many frames without a window and exit
+    #[structopt(long)]
+    frames: Option<u32>,
+    /// Save the last frame of a headless run as png
+    #[structopt(long, parse(from_os_str), requires = "frames")]
+    screenshot: Option<PathBuf>,
+    /// Record every frame of a headless run, as an animated .png or raw .rgb frames
+    #[structopt(long, parse(from_os_str), requires = "frames")]
+    record: Option<PathBuf>,
 }
 
-fn emulation_loop(mut cpu: cpu::CPU) {
+#[derive(StructOpt, Debug)]
+enum Tool {
+    /// Run a rom without a window and save frames as png
+    Dump {
+        #[structopt(parse(from_os_str))]
+        rom: PathBuf,
+        /// Number of frames to run
+        #[structopt(long)]
+        frames: u32,
+        /// Json array of {"frame", "key", "pressed"} events to play back
+        #[structopt(long, parse(from_os_str))]
+        movie: Option<PathBuf>,
+        /// Comma separated frames to save, counting from 0. Every frame when left out
+        #[structopt(long, use_delimiter = true)]
+        save: Vec<u32>,
+        #[structopt(long, parse(from_os_str), default_value = ".")]
+        out_dir: PathBuf,
+    },
+    /// Convert a screen buffer saved as json to png
+    ConvertFixture {
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+        /// Defaults to the input with a .png extension
+        #[structopt(parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+}
+
+/// Save state slots the slot hotkeys cycle through, numbered from 1
+const STATE_SLOTS: u8 = 9;
+
+/// Newest frame the ui thread hasn't drawn yet. Frames the ui doesn't
+/// get around to are replaced, so emulation never waits on the display.
+type FrameSlot = Arc<Mutex<Option<ScreenBuffer>>>;
+
+/// Requests from the ui thread to the emulation thread
+enum Command {
+    TogglePause,
+    FastForward(bool),
+    SlowMotion(bool),
+    FrameAdvance,
+    ToggleCheats,
+    /// Turn the Game Boy off and on again, cartridge ram is kept
+    Reset,
+    SaveState(PathBuf),
+    LoadState(PathBuf),
+    /// Start a ram search, or narrow the running one down with the comparison
+    RamSearch(Option<Comparison>),
+    /// Send a copy of vram after every frame, for the vram viewer
+    WatchVram(Option<Sender<VramSnapshot>>),
+    /// Send every frame, none skipped, for recording
+    Record(Option<Sender<ScreenBuffer>>),
+    /// A line typed into the console, the output goes back over the sender
+    Console(String, Sender<String>),
+}
+
+fn hotkey_command(hotkey: Hotkey, pressed: bool) -> Option<Command> {
+    let filter = |comparison| Some(Command::RamSearch(Some(comparison)));
+    match hotkey {
+        Hotkey::Pause if pressed => Some(Command::TogglePause),
+        Hotkey::FastForward => Some(Command::FastForward(pressed)),
+        Hotkey::SlowMotion => Some(Command::SlowMotion(pressed)),
+        Hotkey::FrameAdvance if pressed => Some(Command::FrameAdvance),
+        Hotkey::Cheats if pressed => Some(Command::ToggleCheats),
+        Hotkey::Reset if pressed => Some(Command::Reset),
+        Hotkey::RamSearch if pressed => Some(Command::RamSearch(None)),
+        Hotkey::RamSearchEqual if pressed => filter(Comparison::Equal),
+        Hotkey::RamSearchChanged if pressed => filter(Comparison::Changed),
+        Hotkey::RamSearchIncreased if pressed => filter(Comparison::Increased),
+        Hotkey::RamSearchDecreased if pressed => filter(Comparison::Decreased),
+        _ => None,
+    }
+}
+
+fn emulation_loop(
+    mut cpu: cpu::CPU,
+    screens: Receiver<ScreenBuffer>,
+    frame_slot: FrameSlot,
+    commands: Receiver<Command>,
+    messages: Sender<String>,
+    (fast_forward, slow_motion): (f64, f64),
+    mut paused: bool,
+) {
+    let fast_forward = if fast_forward > 0.0 {
+        fast_forward
+    } else {
+        f64::INFINITY
+    };
+    let mut pacer = Pacer::new();
+    let mut frames_to_advance = 0;
+    let (mut fast, mut slow) = (false, false);
+    let mut frame_end = cpu.cycles;
+    let mut vram_watcher: Option<Sender<VramSnapshot>> = None;
+    let mut recording: Option<Sender<ScreenBuffer>> = None;
+    let mut search = None;
     loop {
-        cpu.cycle();
-        if cpu.cycles % 4096 == 0 {
-            thread::sleep(time::Duration::from_millis(1));
+        let mut pending: Vec<Command> = commands.try_iter().collect();
+        if paused && frames_to_advance == 0 && pending.is_empty() {
+            match commands.recv() {
+                Ok(command) => pending.push(command),
+                Err(_) => return,
+            }
+        }
+        for command in pending {
+            match command {
+                Command::TogglePause => {
+                    paused = !paused;
+                    frames_to_advance = 0;
+                    pacer.restart();
+                }
+                Command::FastForward(on) => fast = on,
+                Command::SlowMotion(on) => slow = on,
+                Command::FrameAdvance => {
+                    paused = true;
+                    frames_to_advance += 1;
+                }
+                Command::ToggleCheats => {
+                    let cheats = &mut cpu.mmu.cheats;
+                    cheats.enabled = !cheats.enabled;
+                    let state = if cheats.enabled { "on" } else { "off" };
+                    let _ = messages.send(format!("Cheats {}", state));
+                }
+                Command::Reset => {
+                    cpu.power_cycle();
+                    frame_end = cpu.cycles;
+                    pacer.restart();
+                    let _ = messages.send("Reset".to_string());
+                }
+                Command::SaveState(path) => {
+                    let saved = cpu
+                        .save_state()
+                        .and_then(|state| Ok(fs::write(&path, state)?));
+                    let message = match saved {
+                        Ok(()) => format!("Saved state '{}'", path.display()),
+                        Err(e) => format!("Unable to save state '{}': {}", path.display(), e),
+                    };
+                    let _ = messages.send(message);
+                }
+                Command::LoadState(path) => {
+                    let loaded = fs::read(&path)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|state| cpu.load_state(&state));
+                    let message = match loaded {
+                        Ok(()) => {
+                            frame_end = cpu.cycles;
+                            pacer.restart();
+                            format!("Loaded state '{}'", path.display())
+                        }
+                        Err(e) => format!("Unable to load state '{}': {}", path.display(), e),
+                    };
+                    let _ = messages.send(message);
+                }
+                Command::RamSearch(comparison) => {
+                    let message = match (comparison, &mut search) {
+                        (None, _) => {
+                            let started = RamSearch::new(cpu.mmu.snapshot(), Width::U8);
+                            let message = search_summary(&started);
+                            search = Some(started);
+                            message
+                        }
+                        (Some(comparison), Some(search)) => {
+                            search.filter(cpu.mmu.snapshot(), comparison);
+                            search_summary(search)
+                        }
+                        (Some(_), None) => "Start a ram search first".to_string(),
+                    };
+                    let _ = messages.send(message);
+                }
+                Command::WatchVram(watcher) => {
+                    // shows something right away, even when paused
+                    if let Some(watcher) = &watcher {
+                        let _ = watcher.send(VramSnapshot::of(&cpu.mmu.ppu));
+                    }
+                    vram_watcher = watcher;
+                }
+                Command::Record(frames) => recording = frames,
+                Command::Console(line, output) => {
+                    let text = console_command(&mut cpu, &mut search, &line)
+                        .unwrap_or_else(|e| format!("{}\n", e));
+                    let _ = output.send(text);
+                }
+            }
+        }
+        if paused && frames_to_advance == 0 {
+            continue;
+        }
+        pacer.set_speed(match (fast, slow) {
+            (true, _) => fast_forward,
+            (_, true) => slow_motion,
+            _ => 1.0,
+        });
+        frame_end += FRAME_CYCLES as u64;
+        while cpu.cycles < frame_end {
+            cpu.cycle();
+            if let Ok(screen) = screens.try_recv() {
+                if let Some(frames) = &recording {
+                    if frames.send(screen).is_err() {
+                        recording = None;
+                    }
+                }
+                *frame_slot.lock().unwrap() = Some(screen);
+            }
+        }
+        if paused {
+            frames_to_advance -= 1;
+        }
+        if let Some(watcher) = &vram_watcher {
+            if watcher.send(VramSnapshot::of(&cpu.mmu.ppu)).is_err() {
+                vram_watcher = None;
+            }
         }
+        thread::sleep(pacer.advance(FRAME_CYCLES));
     }
 }
 
-fn main() -> Result<()> {
-    let opt:Opt = Opt::from_args();
-    let data = fs::read(&opt.rom).context(format!("unable to open '{}'", opt.rom.display()))?;
-    let sdl_context= sdl2::init().map_err(|s|anyhow::anyhow!(s))?;
-    let mut display = Display::new(&sdl_context);
-    let (tx, rx) = mpsc::sync_channel(0);
+const CONSOLE_HELP: &str = "\
+peek <address> [length]  show memory in hex
+poke <address> <value>   write a byte
+search <u8|i8|u16|i16>   start a ram search over all of ram
+filter <equal|changed|increased|decreased|value>
+                         keep the candidates that compare so to the last filter
+results                  list the candidates
+cheats                   list the cheats
+cheat add <code> [name]  add a GameShark or Game Genie code
+cheat toggle <number>    turn a cheat on or off
+";
+
+/// Reads console lines from stdin until it is closed
+fn console(commands: Sender<Command>) {
+    for line in io::stdin().lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        let (tx, rx) = mpsc::channel();
+        if commands.send(Command::Console(line, tx)).is_err() {
+            return;
+        }
+        if let Ok(text) = rx.recv() {
+            print!("{}", text);
+        }
+    }
+}
+
+fn console_command(
+    cpu: &mut cpu::CPU,
+    search: &mut Option<RamSearch>,
+    line: &str,
+) -> Result<String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let text = match words.as_slice() {
+        [] => String::new(),
+        ["peek", adr] => cpu.mmu.snapshot().hex_dump(parse_address(adr)?, 16),
+        ["peek", adr, len] => cpu
+            .mmu
+            .snapshot()
+            .hex_dump(parse_address(adr)?, len.parse()?),
+        ["poke", adr, value] => {
+            let value = match value.strip_prefix("0x") {
+                Some(hex) => u8::from_str_radix(hex, 16)?,
+                None => value.parse()?,
+            };
+            cpu.mmu.write_word(parse_address(adr)?, value);
+            String::new()
+        }
+        ["search", width] => {
+            let started = RamSearch::new(cpu.mmu.snapshot(), width.parse()?);
+            let text = format!("{} candidates\n", started.candidates().len());
+            *search = Some(started);
+            text
+        }
+        ["filter", comparison] => {
+            let search = search
+                .as_mut()
+                .ok_or_else(|| anyhow!("start a search first"))?;
+            search.filter(cpu.mmu.snapshot(), comparison.parse()?);
+            format!("{} candidates\n", search.candidates().len())
+        }
+        ["results"] => {
+            let search = search
+                .as_ref()
+                .ok_or_else(|| anyhow!("start a search first"))?;
+            let snapshot = search.snapshot();
+            let mut text = String::new();
+            for &adr in search.candidates().iter().take(50) {
+                let value = snapshot.value(adr, search.width()).unwrap_or_default();
+                match snapshot.bank(adr) {
+                    Some(bank) => text += &format!("{:02X}:{:04X} = {}\n", bank, adr, value),
+                    None => text += &format!("{:04X} = {}\n", adr, value),
+                }
+            }
+            if search.candidates().len() > 50 {
+                text += &format!("... {} more\n", search.candidates().len() - 50);
+            }
+            text
+        }
+        ["cheats"] => cpu
+            .mmu
+            .cheats
+            .list()
+            .enumerate()
+            .map(|(i, cheat)| {
+                let state = if cheat.enabled { "on" } else { "off" };
+                format!("{}: {} {} {}\n", i, state, cheat.code, cheat.name)
+            })
+            .collect(),
+        ["cheat", "add", code, name @ ..] => {
+            cpu.mmu.cheats.add(Cheat {
+                code: code.to_string(),
+                name: name.join(" "),
+                enabled: true,
+            })?;
+            String::new()
+        }
+        ["cheat", "toggle", index] => match cpu.mmu.cheats.toggle(index.parse()?) {
+            Some(true) => "on\n".to_string(),
+            Some(false) => "off\n".to_string(),
+            None => bail!("no cheat {}", index),
+        },
+        _ => CONSOLE_HELP.to_string(),
+    };
+    Ok(text)
+}
+
+/// Candidate count for the title bar, with the candidates once few are left
+fn search_summary(search: &RamSearch) -> String {
+    let candidates = search.candidates();
+    let mut summary = format!("Ram search: {} candidates", candidates.len());
+    if !candidates.is_empty() && candidates.len() <= 4 {
+        let snapshot = search.snapshot();
+        let values: Vec<String> = candidates
+            .iter()
+            .map(|&adr| {
+                let value = snapshot.value(adr, search.width()).unwrap_or_default();
+                format!("{:04X}={}", adr, value)
+            })
+            .collect();
+        summary += &format!(" {}", values.join(" "));
+    }
+    summary
+}
+
+/// Hex, with or without a 0x in front
+fn parse_address(s: &str) -> Result<u16> {
+    let hex = s.strip_prefix("0x").unwrap_or(s);
+    u16::from_str_radix(hex, 16).context(format!("invalid address '{}'", s))
+}
+
+fn open_vram_viewer(
+    sdl_context: &sdl2::Sdl,
+    palette: Palette,
+    commands: &Sender<Command>,
+) -> Result<(VramWindow, Receiver<VramSnapshot>)> {
+    let (tx, rx) = mpsc::channel();
+    commands.send(Command::WatchVram(Some(tx)))?;
+    Ok((VramWindow::new(sdl_context, 2, palette), rx))
+}
+
+/// Runs `frames` frames worth of cycles as fast as possible, playing back `movie`.
+/// `on_frame` gets the screen shown at the end of every frame.
+fn run_headless(
+    cpu: &mut cpu::CPU,
+    screens: &Receiver<ScreenBuffer>,
+    inputs: &Sender<Control>,
+    movie: &Movie,
+    frames: u32,
+    mut on_frame: impl FnMut(u32, &ScreenBuffer) -> Result<()>,
+) -> Result<()> {
+    // stays blank until the lcd is turned on
+    let mut screen = BLANK_SCREEN;
+    for frame in 0..frames {
+        for control in movie.controls(frame) {
+            inputs.send(control)?;
+        }
+        let end = cpu.cycles + FRAME_CYCLES as u64;
+        while cpu.cycles < end {
+            cpu.cycle();
+            if let Ok(pixels) = screens.try_recv() {
+                screen = pixels;
+            }
+        }
+        on_frame(frame, &screen)?;
+    }
+    Ok(())
+}
+
+fn start_recording(
+    path: &Path,
+    palette: Palette,
+    commands: &Sender<Command>,
+) -> Result<(Recorder, Receiver<ScreenBuffer>)> {
+    let recorder = Recorder::create(path, palette)?;
+    let (tx, rx) = mpsc::channel();
+    commands.send(Command::Record(Some(tx)))?;
+    Ok((recorder, rx))
+}
+
+/// Records the frames still on their way before finishing the file
+fn stop_recording(
+    (mut recorder, frames): (Recorder, Receiver<ScreenBuffer>),
+    commands: &Sender<Command>,
+) -> Result<()> {
+    // without an emulation thread the frames' sender is gone already
+    let _ = commands.send(Command::Record(None));
+    for frame in frames.iter() {
+        recorder.add_frame(&frame)?;
+    }
+    recorder.finish()
+}
+
+/// `<rom name>-state-<slot>.json` in `dir`
+fn state_path(dir: &Path, rom: &Path, slot: u8) -> PathBuf {
+    let name = rom.file_stem().unwrap_or_default().to_string_lossy();
+    dir.join(format!("{}-state-{}.json", name, slot))
+}
+
+/// First free `<rom name>-<kind>-<n>.png` in `dir`
+fn free_path(dir: &Path, rom: &Path, kind: &str) -> PathBuf {
+    let name = rom.file_stem().unwrap_or_default().to_string_lossy();
+    (1..)
+        .map(|n| dir.join(format!("{}-{}-{}.png", name, kind, n)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+fn load_cpu(opt: &Opt, rom: &Path) -> Result<(cpu::CPU, Receiver<ScreenBuffer>, Sender<Control>)> {
+    let data = fs::read(rom).context(format!("unable to open '{}'", rom.display()))?;
+    let boot_rom = match &opt.boot_rom {
+        Some(path) => Some(fs::read(path).context(format!("unable to open '{}'", path.display()))?),
+        None => None,
+    };
+    // room for one frame so a headless run can collect it after the fact
+    let (tx, rx) = mpsc::sync_channel(1);
     let (tx_events, rx_events) = mpsc::channel();
-    thread::spawn(move || {
-        let mmu = MMU::new(data, tx, rx_events);
-        let mut cpu = cpu::CPU::new(mmu);
-        cpu.reset();
-        emulation_loop(cpu);
+    let mut mmu = MMU::new(data, tx, rx_events);
+    mmu.model = opt.model;
+    if opt.model.is_sgb() {
+        mmu.sgb = Some(Sgb::new());
+    }
+    mmu.joypad.latch_cycle = opt.input_latch_cycle;
+    mmu.joypad.filter_opposite = opt.filter_opposite_directions;
+    if let Some(boot_rom) = boot_rom {
+        mmu.load_boot_rom(boot_rom)?;
+    }
+    let cheats = opt
+        .cheats
+        .clone()
+        .unwrap_or_else(|| rom.with_extension("cheats.toml"));
+    if opt.cheats.is_some() || cheats.exists() {
+        mmu.cheats = Cheats::load(&cheats)?;
+    }
+    if let Some(adr) = &opt.link_listen {
+        mmu.serial.connect(Box::new(TcpPeer::listen(adr)?));
+    } else if let Some(adr) = &opt.link_connect {
+        mmu.serial.connect(Box::new(TcpPeer::connect(adr)?));
+    } else if let Some(dir) = &opt.printer {
+        mmu.serial.connect(Box::new(Printer::new(dir)));
+    }
+    let mut cpu = cpu::CPU::new(mmu);
+    cpu.reset();
+    if let Some(path) = &opt.load_state {
+        let state = fs::read(path).context(format!("unable to open '{}'", path.display()))?;
+        cpu.load_state(&state)
+            .context(format!("unable to load state '{}'", path.display()))?;
+    }
+    Ok((cpu, rx, tx_events))
+}
+
+/// The palette asked for on the command line, or the one the CGB boot rom
+/// would colour the game in
+fn choose_palette(opt: &Opt, cpu: Option<&cpu::CPU>) -> Result<Palette> {
+    if let Some(path) = &opt.palette_file {
+        return Palette::load(path);
+    }
+    if let Some(palette) = opt.palette {
+        return Ok(palette);
+    }
+    match cpu {
+        Some(cpu) if opt.model.is_cgb() => {
+            let header: Vec<u8> = (0..0x150).map(|adr| cpu.mmu.read_word(adr)).collect();
+            Ok(palette::cgb_compat(&header).unwrap_or_default())
+        }
+        _ => Ok(Palette::default()),
+    }
+}
+
+fn dump(
+    opt: &Opt,
+    rom: &Path,
+    frames: u32,
+    movie: Option<&Path>,
+    save: &[u32],
+    out_dir: &Path,
+) -> Result<()> {
+    let movie = match movie {
+        Some(path) => Movie::load(path)?,
+        None => Movie::default(),
+    };
+    let (mut cpu, rx, tx_events) = load_cpu(opt, rom)?;
+    let palette = choose_palette(opt, Some(&cpu))?;
+    fs::create_dir_all(out_dir)?;
+    let name = rom.file_stem().unwrap_or_default().to_string_lossy();
+    run_headless(
+        &mut cpu,
+        &rx,
+        &tx_events,
+        &movie,
+        frames,
+        |frame, screen| {
+            if save.is_empty() || save.contains(&frame) {
+                let path = out_dir.join(format!("{}-{:05}.png", name, frame));
+                save_screen_png(screen, &palette, &path)
+                    .context(format!("unable to save '{}'", path.display()))?;
+            }
+            Ok(())
+        },
+    )
+}
+
+fn main() -> Result<()> {
+    let opt: Opt = Opt::from_args();
+    match &opt.tool {
+        Some(Tool::Dump {
+            rom,
+            frames,
+            movie,
+            save,
+            out_dir,
+        }) => {
+            return dump(&opt, rom, *frames, movie.as_deref(), save, out_dir);
+        }
+        Some(Tool::ConvertFixture { input, output }) => {
+            let screen = load_screen_buffer(input)
+                .context(format!("unable to read '{}'", input.display()))?;
+            let output = output
+                .clone()
+                .unwrap_or_else(|| input.with_extension("png"));
+            return save_screen_png(&screen, &choose_palette(&opt, None)?, &output);
+        }
+        None => {}
+    }
+    let rom = opt
+        .rom
+        .as_ref()
+        .ok_or_else(|| anyhow!("no rom given, see --help"))?;
+    if opt.mute {
+        eprintln!("--mute has no effect, there is no sound emulation yet");
+    }
+    let (mut cpu, rx, tx_events) = load_cpu(&opt, rom)?;
+    let palette = choose_palette(&opt, Some(&cpu))?;
+
+    if let Some(frames) = opt.frames {
+        let mut recorder = match &opt.record {
+            Some(path) => Some(Recorder::create(path, palette)?),
+            None => None,
+        };
+        run_headless(
+            &mut cpu,
+            &rx,
+            &tx_events,
+            &Movie::default(),
+            frames,
+            |frame, screen| {
+                if let Some(recorder) = &mut recorder {
+                    recorder.add_frame(screen)?;
+                }
+                match &opt.screenshot {
+                    Some(path) if frame + 1 == frames => save_screen_png(screen, &palette, path)
+                        .context(format!("unable to save '{}'", path.display())),
+                    _ => Ok(()),
+                }
+            },
+        )?;
+        if let Some(recorder) = recorder {
+            recorder.finish()?;
+        }
+        return Ok(());
+    }
+
+    let bindings_config = match &opt.bindings {
+        Some(path) => BindingsConfig::load(path)?,
+        None => BindingsConfig::default(),
+    };
+    let mut bindings = Bindings::new(&bindings_config)?;
+    let sdl_context = sdl2::init().map_err(|s| anyhow::anyhow!(s))?;
+    // with the SGB its bordered frames are drawn instead of the plain screen
+    let sgb_frames = cpu.mmu.sgb.as_mut().map(|sgb| {
+        let (tx, rx) = mpsc::channel();
+        sgb.frame_sender = Some(tx);
+        rx
     });
+    let size = match sgb_frames {
+        Some(_) => (SGB_WIDTH as u32, SGB_HEIGHT as u32),
+        None => (160, 144),
+    };
+    let mut display = Display::new(
+        &sdl_context,
+        size,
+        opt.scale,
+        opt.fullscreen,
+        palette,
+        opt.filter,
+        opt.ghosting,
+    );
+    let mut controllers = Controllers::new(&sdl_context)?;
+    let (tx_commands, rx_commands) = mpsc::channel();
+    let (tx_messages, rx_messages) = mpsc::channel();
+    let (fast_forward, slow_motion, paused) = (opt.fast_forward, opt.slow_motion, opt.paused);
+    if opt.console {
+        let commands = tx_commands.clone();
+        thread::spawn(move || console(commands));
+    }
+    let frame_slot = FrameSlot::default();
+    let emulation = {
+        let frame_slot = frame_slot.clone();
+        thread::spawn(move || {
+            emulation_loop(
+                cpu,
+                rx,
+                frame_slot,
+                rx_commands,
+                tx_messages,
+                (fast_forward, slow_motion),
+                paused,
+            );
+        })
+    };
     let mut event_pump = sdl_context.event_pump().unwrap();
-    let mut redraws = 0u64;
+    let mut screen = BLANK_SCREEN;
+    let mut sgb_frame = Vec::new();
+    let mut recording = None;
+    let mut vram_viewer = None;
+    let mut state_slot = 1;
+    if opt.vram_viewer {
+        vram_viewer = Some(open_vram_viewer(&sdl_context, palette, &tx_commands)?);
+    }
     loop {
         for event in event_pump.poll_iter() {
-            match from_sdl2_event(event) {
-                None => {},
-                Some(Control::Quit) => std::process::exit(0),
-                Some(x) => tx_events.send(x)?
+            controllers.handle_event(&event);
+            let mut controls = bindings.translate(&event);
+            // with two windows open closing one doesn't quit by itself
+            if let Event::Window {
+                window_id,
+                win_event: WindowEvent::Close,
+                ..
+            } = event
+            {
+                match &vram_viewer {
+                    Some((window, _)) if window.window_id() == window_id => {
+                        controls.push(Control::HotkeyDown(Hotkey::VramViewer))
+                    }
+                    _ => controls.push(Control::Quit),
+                }
+            }
+            for control in controls {
+                match control {
+                    Control::Quit => {
+                        if let Some(recording) = recording.take() {
+                            stop_recording(recording, &tx_commands)?;
+                        }
+                        std::process::exit(0)
+                    }
+                    Control::HotkeyDown(Hotkey::Screenshot) => {
+                        let path = free_path(&opt.save_dir, rom, "screenshot");
+                        if let Err(e) = save_screen_png(&screen, &palette, &path) {
+                            eprintln!("Unable to save screenshot '{}': {}", path.display(), e);
+                        }
+                    }
+                    Control::HotkeyDown(Hotkey::Fullscreen) => display.toggle_fullscreen(),
+                    Control::HotkeyDown(Hotkey::VramViewer) => match vram_viewer.take() {
+                        Some(_) => tx_commands.send(Command::WatchVram(None))?,
+                        None => {
+                            vram_viewer =
+                                Some(open_vram_viewer(&sdl_context, palette, &tx_commands)?)
+                        }
+                    },
+                    Control::HotkeyDown(Hotkey::Record) => match recording.take() {
+                        Some(recording) => stop_recording(recording, &tx_commands)?,
+                        None => {
+                            let path = free_path(&opt.save_dir, rom, "recording");
+                            recording = Some(start_recording(&path, palette, &tx_commands)?);
+                        }
+                    },
+                    Control::HotkeyDown(Hotkey::SaveState) => {
+                        let path = state_path(&opt.save_dir, rom, state_slot);
+                        tx_commands.send(Command::SaveState(path))?
+                    }
+                    Control::HotkeyDown(Hotkey::LoadState) => {
+                        let path = state_path(&opt.save_dir, rom, state_slot);
+                        tx_commands.send(Command::LoadState(path))?
+                    }
+                    Control::HotkeyDown(Hotkey::NextSlot) => {
+                        state_slot = state_slot % STATE_SLOTS + 1;
+                        display.show_message(&format!("State slot {}", state_slot));
+                    }
+                    Control::HotkeyDown(Hotkey::PreviousSlot) => {
+                        state_slot = (state_slot + STATE_SLOTS - 2) % STATE_SLOTS + 1;
+                        display.show_message(&format!("State slot {}", state_slot));
+                    }
+                    Control::HotkeyDown(hotkey) => {
+                        if let Some(command) = hotkey_command(hotkey, true) {
+                            tx_commands.send(command)?;
+                        }
+                    }
+                    Control::HotkeyUp(hotkey) => {
+                        if let Some(command) = hotkey_command(hotkey, false) {
+                            tx_commands.send(command)?;
+                        }
+                    }
+                    x => tx_events.send(x)?,
+                }
+            }
+        }
+        if emulation.is_finished() {
+            break;
+        }
+        // when fast-forwarding only the newest frame is drawn, but all are recorded
+        if let Some((recorder, frames)) = &mut recording {
+            for frame in frames.try_iter() {
+                recorder.add_frame(&frame)?;
             }
         }
-        match &rx.try_recv() {
-            Ok(pixels) => {
-                redraws += 1;
-                display.draw(pixels);
-                                if redraws == 200 {
-                                    save_screen_buffer(pixels, "test.json".to_string());
-                                    std::process::exit(0);
-                                }
+        // a poisoned slot means emulation panicked, which ends the loop above
+        match frame_slot.lock().ok().and_then(|mut slot| slot.take()) {
+            Some(pixels) => {
+                screen = pixels;
+                match &sgb_frames {
+                    Some(frames) => {
+                        if let Some(frame) = frames.try_iter().last() {
+                            sgb_frame = frame;
+                        }
+                        if !sgb_frame.is_empty() {
+                            display.draw_colours(&sgb_frame);
+                        }
+                    }
+                    None => display.draw(&screen),
+                }
             }
-            Err(TryRecvError::Empty) => {}
-            Err(TryRecvError::Disconnected) => {}
+            None => thread::sleep(time::Duration::from_millis(5)),
+        }
+        if let Some((window, snapshots)) = &mut vram_viewer {
+            if let Some(snapshot) = snapshots.try_iter().last() {
+                window.draw(&snapshot);
+            }
+        }
+        match rx_messages.try_iter().last() {
+            Some(message) => display.show_message(&message),
+            None => display.expire_message(),
+        }
+    }
+    if let Some(recording) = recording.take() {
+        stop_recording(recording, &tx_commands)?;
+    }
+    match emulation.join() {
+        Ok(()) => bail!("emulation stopped"),
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            bail!("emulation stopped: {}", message)
         }
-        thread::sleep(time::Duration::from_millis(5));
     }
 }
@@ -0,0 +1,266 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheatCode {
+    /// `BBVVAAAA`: writes value VV to address AAAA (little endian) every VBlank.
+    /// Bank 01 is whatever is mapped, 8x only cartridge ram bank x and 9x work ram bank x.
+    GameShark { bank: u8, value: u8, adr: u16 },
+    /// `VVA-AAA` or `VVA-AAA-CCC`: rom reads of the address return VV
+    /// instead, only when the rom holds the compare value if one is given
+    GameGenie {
+        adr: u16,
+        value: u8,
+        compare: Option<u8>,
+    },
+}
+
+impl FromStr for CheatCode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits: Vec<u8> = s
+            .chars()
+            .filter(|&c| c != '-')
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<_>>()
+            .ok_or_else(|| anyhow!("cheat code '{}' isn't hex", s))?;
+        let byte = |i: usize| digits[i] << 4 | digits[i + 1];
+        match (s.contains('-'), digits.len()) {
+            (false, 8) => {
+                let adr = u16::from_le_bytes([byte(4), byte(6)]);
+                if !is_ram(adr) {
+                    bail!(
+                        "GameShark code '{}' writes to {:04X}, which isn't ram",
+                        s,
+                        adr
+                    );
+                }
+                Ok(CheatCode::GameShark {
+                    bank: byte(0),
+                    value: byte(2),
+                    adr,
+                })
+            }
+            (true, 6) | (true, 9) if s.split('-').all(|group| group.len() == 3) => {
+                let adr = ((digits[5] as u16 ^ 0xF) << 12)
+                    | (digits[2] as u16) << 8
+                    | (digits[3] as u16) << 4
+                    | digits[4] as u16;
+                // the eighth digit is only there to make codes harder to guess
+                let compare = if digits.len() == 9 {
+                    Some((digits[6] << 4 | digits[8]).rotate_right(2) ^ 0xBA)
+                } else {
+                    None
+                };
+                Ok(CheatCode::GameGenie {
+                    adr,
+                    value: byte(0),
+                    compare,
+                })
+            }
+            _ => bail!("'{}' is neither a GameShark nor a Game Genie code", s),
+        }
+    }
+}
+
+/// Cartridge ram, work ram and high ram, where GameShark codes may write
+fn is_ram(adr: u16) -> bool {
+    matches!(adr, 0xA000..=0xDFFF | 0xFF80..=0xFFFE)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cheat {
+    pub code: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default = "enabled_default")]
+    pub enabled: bool,
+}
+
+fn enabled_default() -> bool {
+    true
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CheatFile {
+    #[serde(default, rename = "cheat")]
+    cheats: Vec<Cheat>,
+}
+
+/// The cheats of a game. They are kept apart from the emulated hardware so
+/// resetting or restoring its state leaves them as they are.
+pub struct Cheats {
+    cheats: Vec<(Cheat, CheatCode)>,
+    /// Turns all cheats off without forgetting which are enabled
+    pub enabled: bool,
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Cheats {
+            cheats: Vec::new(),
+            enabled: true,
+        }
+    }
+
+    /// Reads a toml file of `[[cheat]]` tables with `code`, `name` and `enabled`
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("unable to open '{}'", path.display()))?;
+        let file: CheatFile = toml::from_str(&text)?;
+        let mut cheats = Cheats::new();
+        for cheat in file.cheats {
+            cheats.add(cheat)?;
+        }
+        Ok(cheats)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = CheatFile {
+            cheats: self.list().cloned().collect(),
+        };
+        fs::write(path, toml::to_string(&file)?)?;
+        Ok(())
+    }
+
+    pub fn add(&mut self, cheat: Cheat) -> Result<()> {
+        let code = cheat.code.parse()?;
+        self.cheats.push((cheat, code));
+        Ok(())
+    }
+
+    pub fn list(&self) -> impl Iterator<Item = &Cheat> {
+        self.cheats.iter().map(|(cheat, _)| cheat)
+    }
+
+    /// Returns the new state, `None` if there is no such cheat
+    pub fn toggle(&mut self, index: usize) -> Option<bool> {
+        let (cheat, _) = self.cheats.get_mut(index)?;
+        cheat.enabled = !cheat.enabled;
+        Some(cheat.enabled)
+    }
+
+    fn active(&self) -> impl Iterator<Item = &CheatCode> {
+        let enabled = self.enabled;
+        self.cheats
+            .iter()
+            .filter(move |(cheat, _)| enabled && cheat.enabled)
+            .map(|(_, code)| code)
+    }
+
+    /// The value a rom read of `adr` returns, given what the cartridge holds
+    pub fn patch_rom(&self, adr: u16, rom_value: u8) -> u8 {
+        for code in self.active() {
+            if let CheatCode::GameGenie {
+                adr: patched,
+                value,
+                compare,
+            } = *code
+            {
+                if patched == adr && compare.is_none_or(|c| c == rom_value) {
+                    return value;
+                }
+            }
+        }
+        rom_value
+    }
+
+    /// Address and value of every GameShark write due, given the cartridge
+    /// ram bank mapped
+    pub fn ram_writes(&self, ram_bank: usize) -> Vec<(u16, u8)> {
+        self.active()
+            .filter_map(|code| match *code {
+                CheatCode::GameShark { bank, value, adr } => {
+                    let applies = match (bank >> 4, adr) {
+                        (0x8, 0xA000..=0xBFFF) => (bank & 0xF) as usize == ram_bank,
+                        // without CGB banking, bank 1 is the only one at 0xD000
+                        (0x9, 0xD000..=0xDFFF) => bank & 0xF <= 1,
+                        // any other bank writes to whatever is mapped
+                        _ => is_ram(adr),
+                    };
+                    if applies {
+                        Some((adr, value))
+                    } else {
+                        None
+                    }
+                }
+                CheatCode::GameGenie { .. } => None,
+            })
+            .collect()
+    }
+}
+
+impl Default for Cheats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cheat(code: &str) -> Cheat {
+        Cheat {
+            code: code.to_string(),
+            name: String::new(),
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn test_parse_codes() {
+        assert_eq!(
+            "010138CD".parse::<CheatCode>().unwrap(),
+            CheatCode::GameShark {
+                bank: 0x01,
+                value: 0x01,
+                adr: 0xCD38
+            }
+        );
+        assert_eq!(
+            "00A-17B-C49".parse::<CheatCode>().unwrap(),
+            CheatCode::GameGenie {
+                adr: 0x4A17,
+                value: 0x00,
+                compare: Some(0xC8),
+            }
+        );
+        assert!("01-2345".parse::<CheatCode>().is_err());
+    }
+
+    #[test]
+    fn test_gameshark_codes_only_write_ram() {
+        assert!("01FF80FF".parse::<CheatCode>().is_ok());
+        assert!("8102FFBF".parse::<CheatCode>().is_ok());
+        // rom, vram, echo ram, oam and io
+        for code in &[
+            "01010040", "01000080", "010000E0", "010000FE", "010040FF", "01FFFFFF",
+        ] {
+            assert!(code.parse::<CheatCode>().is_err(), "{}", code);
+        }
+        let mut cheats = Cheats::new();
+        assert!(cheats.add(cheat("01010040")).is_err());
+        assert!(cheats.list().next().is_none());
+    }
+
+    #[test]
+    fn test_patches_and_toggle() {
+        let mut cheats = Cheats::new();
+        cheats.add(cheat("00A-17B-C49")).unwrap();
+        cheats.add(cheat("010138CD")).unwrap();
+        assert_eq!(cheats.patch_rom(0x4A17, 0xC8), 0x00);
+        // a different rom bank holds something else there
+        assert_eq!(cheats.patch_rom(0x4A17, 0x12), 0x12);
+        assert_eq!(cheats.ram_writes(0), vec![(0xCD38, 0x01)]);
+        assert_eq!(cheats.toggle(1), Some(false));
+        assert!(cheats.ram_writes(0).is_empty());
+        cheats.enabled = false;
+        assert_eq!(cheats.patch_rom(0x4A17, 0xC8), 0xC8);
+    }
+}
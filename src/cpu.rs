@@ -1,8 +1,11 @@
 use std::fmt::Debug;
 
-use crate::Interrupt;
+use serde::{Deserialize, Serialize};
+
 use crate::mmu::MMU;
-use crate::registers::{R16, R8, RegIO, Registers};
+use crate::registers::{RegIO, Registers, R16, R8};
+use crate::savestate::{unconnected_receiver, unconnected_sender};
+use crate::Interrupt;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct LitU8;
@@ -170,6 +173,7 @@ impl Target<u16> for Mem {
     }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct CPU {
     pub mmu: MMU,
     ime: bool, //Interrupt master enable flag
@@ -177,6 +181,8 @@ pub struct CPU {
     pub regs: Registers,
     pub cycles: u64,
     is_halted: bool,
+    /// Set when `LD B,B` is executed, test roms use it as a software breakpoint
+    pub breakpoint: bool,
 }
 
 // Todo: Use union and unsafe for u16/u8u8 registers?
@@ -189,46 +195,39 @@ impl CPU {
             regs: Registers::new(),
             cycles: 0,
             is_halted: false,
+            breakpoint: false,
         }
     }
 
+    /// Puts the cpu in its power-on state. With a boot rom mapped execution starts
+    /// at 0x0000, otherwise the state the boot rom leaves behind is set up directly.
     pub fn reset(&mut self) {
-        self.regs.set_reg_af(0x01B0);
-        self.regs.set_reg_bc(0x0013);
-        self.regs.set_reg_de(0x00D8);
-        self.regs.set_reg_hl(0x014D);
+        if self.mmu.is_boot_rom_mapped() {
+            self.regs = Registers::new();
+            self.regs.pc = 0x0000;
+        } else {
+            self.skip_boot();
+        }
+    }
+
+    fn skip_boot(&mut self) {
+        let (af, bc, de, hl) = self.mmu.model.registers();
+        self.regs.set_reg_af(af);
+        self.regs.set_reg_bc(bc);
+        self.regs.set_reg_de(de);
+        self.regs.set_reg_hl(hl);
         self.regs.sp = 0xFFFE;
-        self.mmu.write_word(0xFF05, 0);
-        self.mmu.write_word(0xFF06, 0);
-        self.mmu.write_word(0xFF07, 0);
-        self.mmu.write_word(0xFF10, 0x80);
-        self.mmu.write_word(0xFF11, 0xBF);
-        self.mmu.write_word(0xFF12, 0xF3);
-        self.mmu.write_word(0xFF14, 0xBF);
-        self.mmu.write_word(0xFF16, 0x3F);
-        self.mmu.write_word(0xFF17, 0);
-        self.mmu.write_word(0xFF19, 0xBF);
-        self.mmu.write_word(0xFF1A, 0x7F);
-        self.mmu.write_word(0xFF1B, 0xFF);
-        self.mmu.write_word(0xFF1C, 0x9F);
-        self.mmu.write_word(0xFF1E, 0xBF);
-        self.mmu.write_word(0xFF20, 0xFF);
-        self.mmu.write_word(0xFF21, 0);
-        self.mmu.write_word(0xFF22, 0);
-        self.mmu.write_word(0xFF23, 0xBF);
-        self.mmu.write_word(0xFF24, 0x77);
-        self.mmu.write_word(0xFF25, 0xF3);
-        self.mmu.write_word(0xFF26, 0xF1);
-        self.mmu.write_word(0xFF40, 0x91); // LCDC
-        self.mmu.write_word(0xFF42, 0); // SCY
-        self.mmu.write_word(0xFF43, 0); // SCX
-        self.mmu.write_word(0xFF45, 0); // LYC
-        self.mmu.write_word(0xFF47, 0xFC); // BGP
-        self.mmu.write_word(0xFF48, 0xFF); // OBP0
-        self.mmu.write_word(0xFF49, 0xFF); // OBP1
-        self.mmu.write_word(0xFF4A, 0); // WY
-        self.mmu.write_word(0xFF4B, 0); // WX
-        self.mmu.write_word(0xFFFF, 0); // IE
+        self.regs.pc = 0x0100;
+        self.mmu.skip_boot();
+    }
+
+    /// Resets everything the way turning the Game Boy off and on does,
+    /// keeping the cartridge ram
+    pub fn power_cycle(&mut self) {
+        let mut mmu = MMU::new(Vec::new(), unconnected_sender(), unconnected_receiver());
+        mmu.power_cycled(&mut self.mmu);
+        *self = CPU::new(mmu);
+        self.reset();
     }
 
     pub fn ld<T>(&mut self, target: impl Target<T>, source: impl Source<T>) {
@@ -888,7 +887,10 @@ impl CPU {
             0x3D => self.dec_u8(A),
             0x3E => self.ld(A, LitU8),
             0x3F => self.ccf(),
-            0x40 => self.ld(B, B),
+            0x40 => {
+                self.ld(B, B);
+                self.breakpoint = true;
+            }
             0x41 => self.ld(B, C),
             0x42 => self.ld(B, D),
             0x43 => self.ld(B, E),
@@ -1,78 +1,199 @@
-use sdl2::render::Canvas;
-use sdl2::video::Window;
-use std::error::Error;
+use std::time::{Duration, Instant};
 
-const SCALE_FACTOR: usize = 4;
-const SCREEN_WIDTH: usize = 160;
-const SCREEN_HEIGHT: usize = 144;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Rect;
+use sdl2::render::{Canvas, Texture};
+use sdl2::video::{FullscreenType, Window};
 
-const WHITE: (u8, u8, u8) = (224, 248, 208);
-const LIGHT_GRAY: (u8, u8, u8) = (136, 192, 112);
-const DARK_GRAY: (u8, u8, u8) = (52, 104, 86);
-const BLACK: (u8, u8, u8) = (8, 24, 32);
+use crate::filter::{Filter, FrameFilter};
+use crate::palette::{Colour, Palette};
+use crate::vram::{Image, VramSnapshot};
+use crate::ScreenBuffer;
+
+const TITLE: &str = "Chipsand";
+/// How long a message stays in the title bar
+const MESSAGE_TIME: Duration = Duration::from_secs(3);
 
 pub struct Display {
     canvas: Canvas<Window>,
+    texture: Texture,
+    filter: FrameFilter,
+    palette: Palette,
+    width: u32,
+    height: u32,
+    message_since: Option<Instant>,
 }
 
 impl Display {
-    pub fn new(sdl_context: &sdl2::Sdl) -> Self {
+    /// `size` is the size of the frames drawn, 160x144 or 256x224 with the SGB border
+    pub fn new(
+        sdl_context: &sdl2::Sdl,
+        (width, height): (u32, u32),
+        scale: u32,
+        fullscreen: bool,
+        palette: Palette,
+        filter: Filter,
+        ghosting: bool,
+    ) -> Self {
         let video_subsys = sdl_context.video().unwrap();
-        let window = video_subsys
-            .window(
-                "Chipsand",
-                (SCREEN_WIDTH * SCALE_FACTOR) as u32,
-                (SCREEN_HEIGHT * SCALE_FACTOR) as u32,
-            )
-            .position_centered()
-            .opengl()
+        let mut window = video_subsys.window(TITLE, width * scale, height * scale);
+        window.position_centered().resizable();
+        if fullscreen {
+            window.fullscreen_desktop();
+        }
+        let window = window.build().unwrap();
+
+        let mut canvas = window
+            .into_canvas()
+            .accelerated()
+            .present_vsync()
             .build()
             .unwrap();
-
-        let mut canvas = window.into_canvas().build().unwrap();
-        canvas.set_scale(4 as f32, 4 as f32).unwrap();
+        let filter = FrameFilter::new(filter, ghosting, width as usize, height as usize);
+        let (texture_width, texture_height) = filter.size();
+        let texture = canvas
+            .create_texture_streaming(
+                PixelFormatEnum::RGB24,
+                texture_width as u32,
+                texture_height as u32,
+            )
+            .unwrap();
         canvas.clear();
         canvas.present();
 
-        Display { canvas }
+        Display {
+            canvas,
+            texture,
+            filter,
+            palette,
+            width,
+            height,
+            message_since: None,
+        }
     }
 
-    pub fn draw(&mut self, pixels: &[[u8; SCREEN_WIDTH]; SCREEN_HEIGHT]) {
-        for (y, row) in pixels.iter().enumerate() {
-            for (x, &col) in row.iter().enumerate() {
-                let col = match col {
-                    0 => WHITE,
-                    1 => LIGHT_GRAY,
-                    2 => DARK_GRAY,
-                    3 => BLACK,
-                    _ => panic!(),
-                };
-                self.canvas.set_draw_color(col);
-                self.canvas.draw_point((x as i32, y as i32)).unwrap();
+    /// Shows a short note like "Cheats off" in the title bar for a few seconds
+    pub fn show_message(&mut self, message: &str) {
+        let title = format!("{} - {}", TITLE, message);
+        if self.canvas.window_mut().set_title(&title).is_ok() {
+            self.message_since = Some(Instant::now());
+        }
+    }
+
+    /// Puts the plain title back once the message has been up long enough
+    pub fn expire_message(&mut self) {
+        if let Some(since) = self.message_since {
+            if since.elapsed() > MESSAGE_TIME {
+                let _ = self.canvas.window_mut().set_title(TITLE);
+                self.message_since = None;
             }
         }
+    }
+
+    pub fn toggle_fullscreen(&mut self) {
+        let window = self.canvas.window_mut();
+        let state = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        if let Err(e) = window.set_fullscreen(state) {
+            eprintln!("Unable to toggle fullscreen: {}", e);
+        }
+    }
+
+    pub fn draw(&mut self, pixels: &ScreenBuffer) {
+        let frame = self.filter.apply(pixels, &self.palette);
+        self.present(&frame);
+    }
+
+    /// Draws a frame coloured by the SGB
+    pub fn draw_colours(&mut self, colours: &[Colour]) {
+        let frame = self.filter.apply_colours(colours.to_vec());
+        self.present(&frame);
+    }
+
+    fn present(&mut self, frame: &[u8]) {
+        let (width, _) = self.filter.size();
+        self.texture.update(None, frame, width * 3).unwrap();
+        let (output_width, output_height) = self.canvas.output_size().unwrap();
+        self.canvas.clear();
+        self.canvas
+            .copy(
+                &self.texture,
+                None,
+                viewport(output_width, output_height, self.width, self.height),
+            )
+            .unwrap();
         self.canvas.present();
     }
+}
+
+/// Largest integer multiple of the screen that fits the window, centred
+fn viewport(width: u32, height: u32, screen_width: u32, screen_height: u32) -> Rect {
+    let scale = (width / screen_width).min(height / screen_height).max(1);
+    let (w, h) = (screen_width * scale, screen_height * scale);
+    Rect::new(
+        (width as i32 - w as i32) / 2,
+        (height as i32 - h as i32) / 2,
+        w,
+        h,
+    )
+}
 
-    pub fn save_screenshot(&mut self) -> Result<(), Box<dyn Error>> {
-        let (scale_x, scale_y) = self.canvas.scale();
-        self.canvas.set_scale(1 as f32, 1 as f32)?;
-        {
-            let canvas = &self.canvas;
-            let pixel_format = canvas.default_pixel_format();
-            let mut pixels = canvas.read_pixels(canvas.viewport(), pixel_format).unwrap();
-            let (width, height) = canvas.output_size().unwrap();
-            let pitch = pixel_format.byte_size_of_pixels(width as usize);
-            let surf = sdl2::surface::Surface::from_data(
-                pixels.as_mut_slice(),
-                width,
-                height,
-                pitch as u32,
-                pixel_format,
-            ).unwrap();
-            surf.save_bmp("test.bmp")?;
+/// Second window showing the tile data, sprites and both background maps
+pub struct VramWindow {
+    canvas: Canvas<Window>,
+    texture: Texture,
+    palette: Palette,
+}
+
+// tiles over sprites on the left, then map 0 and map 1
+const VRAM_WINDOW_WIDTH: u32 = 128 + 256 * 2;
+const VRAM_WINDOW_HEIGHT: u32 = 192 + 80;
+
+impl VramWindow {
+    pub fn new(sdl_context: &sdl2::Sdl, scale: u32, palette: Palette) -> Self {
+        let video_subsys = sdl_context.video().unwrap();
+        let window = video_subsys
+            .window(
+                "Chipsand VRAM",
+                VRAM_WINDOW_WIDTH * scale,
+                VRAM_WINDOW_HEIGHT * scale,
+            )
+            .resizable()
+            .build()
+            .unwrap();
+        let canvas = window.into_canvas().accelerated().build().unwrap();
+        let texture = canvas
+            .texture_creator()
+            .create_texture_streaming(
+                PixelFormatEnum::RGB24,
+                VRAM_WINDOW_WIDTH,
+                VRAM_WINDOW_HEIGHT,
+            )
+            .unwrap();
+        VramWindow {
+            canvas,
+            texture,
+            palette,
         }
-        self.canvas.set_scale(scale_x, scale_y)?;
-        Ok(())
+    }
+
+    pub fn window_id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    pub fn draw(&mut self, snapshot: &VramSnapshot) {
+        let mut image = Image::new(VRAM_WINDOW_WIDTH as usize, VRAM_WINDOW_HEIGHT as usize);
+        image.blit(&snapshot.tiles(snapshot.bgp, &self.palette), 0, 0);
+        image.blit(&snapshot.sprites(&self.palette), 0, 192);
+        image.blit(&snapshot.tile_map(0, &self.palette), 128, 0);
+        image.blit(&snapshot.tile_map(1, &self.palette), 128 + 256, 0);
+        self.texture
+            .update(None, &image.to_rgb(), image.width * 3)
+            .unwrap();
+        self.canvas.clear();
+        self.canvas.copy(&self.texture, None, None).unwrap();
+        self.canvas.present();
     }
 }
@@ -0,0 +1,96 @@
+use serde::{Deserialize, Serialize};
+
+const DMA_LENGTH: u16 = 160;
+// Cycles between writing 0xFF46 and the first byte being copied
+const DMA_STARTUP: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bus {
+    External,
+    Video,
+    Oam,
+    Internal,
+}
+
+impl Bus {
+    pub fn of(adr: u16) -> Self {
+        match adr {
+            0x8000..=0x9FFF => Bus::Video,
+            0xFE00..=0xFEFF => Bus::Oam,
+            0xFF00..=0xFFFF => Bus::Internal,
+            _ => Bus::External,
+        }
+    }
+}
+
+/// OAM DMA acting as a second bus master next to the cpu.
+#[derive(Serialize, Deserialize)]
+pub struct OamDma {
+    pub reg: u8,
+    source: u16,
+    offset: u16,
+    active: bool,
+    pending: Option<(u16, u8)>,
+    pub last_value: u8,
+}
+
+impl OamDma {
+    pub(crate) fn new() -> Self {
+        OamDma {
+            reg: 0,
+            source: 0,
+            offset: 0,
+            active: false,
+            pending: None,
+            last_value: 0xFF,
+        }
+    }
+
+    /// A running transfer keeps going until the restarted one has passed its startup delay
+    pub fn start(&mut self, high_adr: u8) {
+        self.reg = high_adr;
+        // sources above 0xDFFF are mirrors of wram
+        let source = match high_adr {
+            0xE0..=0xFF => (high_adr as u16 - 0x20) << 8,
+            _ => (high_adr as u16) << 8,
+        };
+        self.pending = Some((source, DMA_STARTUP));
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Bus currently driven by the transfer, if any
+    pub fn bus(&self) -> Option<Bus> {
+        if self.active {
+            Some(Bus::of(self.source))
+        } else {
+            None
+        }
+    }
+
+    /// Advances one M-cycle, returning the source address and oam offset to copy
+    pub fn tick(&mut self) -> Option<(u16, u16)> {
+        // the bus stays taken for the whole cycle the last byte is copied in
+        if self.active && self.offset == DMA_LENGTH {
+            self.active = false;
+        }
+        if let Some((source, delay)) = self.pending {
+            if delay == 0 {
+                self.pending = None;
+                self.source = source;
+                self.offset = 0;
+                self.active = true;
+            } else {
+                self.pending = Some((source, delay - 1));
+            }
+        }
+        if !self.active {
+            return None;
+        }
+        let transfer = (self.source + self.offset, self.offset);
+        self.offset += 1;
+        Some(transfer)
+    }
+}
@@ -0,0 +1,190 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+
+use crate::palette::{Colour, Palette};
+use crate::ScreenBuffer;
+
+/// Upscaling done on the cpu before the frame is handed to the gpu
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    Nearest,
+    Scale2x,
+    /// Dark gaps between pixels like on the original lcd
+    LcdGrid,
+}
+
+impl Filter {
+    /// How many times larger the filtered frame is than the screen
+    pub fn factor(&self) -> usize {
+        match self {
+            Filter::Nearest => 1,
+            Filter::Scale2x => 2,
+            Filter::LcdGrid => 3,
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "nearest" => Ok(Filter::Nearest),
+            "scale2x" => Ok(Filter::Scale2x),
+            "lcd" => Ok(Filter::LcdGrid),
+            _ => Err(anyhow!("unknown filter '{}'", s)),
+        }
+    }
+}
+
+/// Turns screen buffers into rgb frames, optionally blending each frame with
+/// the previous one to mimic the slow lcd. Games rely on that ghosting to make
+/// sprites flickering every other frame look transparent.
+pub struct FrameFilter {
+    filter: Filter,
+    ghosting: bool,
+    width: usize,
+    height: usize,
+    previous: Vec<Colour>,
+}
+
+impl FrameFilter {
+    /// Filters frames of `width` by `height` pixels, 160x144 unless the SGB
+    /// border is shown
+    pub fn new(filter: Filter, ghosting: bool, width: usize, height: usize) -> Self {
+        FrameFilter {
+            filter,
+            ghosting,
+            width,
+            height,
+            previous: Vec::new(),
+        }
+    }
+
+    /// Width and height of the frames returned by `apply`
+    pub fn size(&self) -> (usize, usize) {
+        (
+            self.width * self.filter.factor(),
+            self.height * self.filter.factor(),
+        )
+    }
+
+    /// Returns the frame as rgb24
+    pub fn apply(&mut self, screen: &ScreenBuffer, palette: &Palette) -> Vec<u8> {
+        let colours = screen
+            .iter()
+            .flat_map(|row| row.iter())
+            .map(|&pixel| palette.colour(pixel))
+            .collect();
+        self.apply_colours(colours)
+    }
+
+    /// Same as `apply` for a frame that is already coloured
+    pub fn apply_colours(&mut self, mut colours: Vec<Colour>) -> Vec<u8> {
+        if self.ghosting {
+            if self.previous.len() == colours.len() {
+                for (colour, previous) in colours.iter_mut().zip(&self.previous) {
+                    *colour = blend(*colour, *previous);
+                }
+            }
+            self.previous = colours.clone();
+        }
+        let colours = match self.filter {
+            Filter::Nearest => colours,
+            Filter::Scale2x => scale2x(&colours, self.width, self.height),
+            Filter::LcdGrid => lcd_grid(&colours, self.width, self.height),
+        };
+        colours
+            .iter()
+            .flat_map(|&(r, g, b)| vec![r, g, b])
+            .collect()
+    }
+}
+
+fn blend(a: Colour, b: Colour) -> Colour {
+    let mix = |a: u8, b: u8| ((a as u16 + b as u16) / 2) as u8;
+    (mix(a.0, b.0), mix(a.1, b.1), mix(a.2, b.2))
+}
+
+/// Scale2x/EPX, doubles the size while keeping diagonal edges sharp
+fn scale2x(src: &[Colour], width: usize, height: usize) -> Vec<Colour> {
+    let at = |x: usize, y: usize| src[y * width + x];
+    let mut out = vec![(0, 0, 0); width * height * 4];
+    for y in 0..height {
+        for x in 0..width {
+            let p = at(x, y);
+            let a = at(x, y.saturating_sub(1));
+            let b = at((x + 1).min(width - 1), y);
+            let c = at(x.saturating_sub(1), y);
+            let d = at(x, (y + 1).min(height - 1));
+            let mut e = [p; 4];
+            if c == a && c != d && a != b {
+                e[0] = a;
+            }
+            if a == b && a != c && b != d {
+                e[1] = b;
+            }
+            if d == c && d != b && c != a {
+                e[2] = c;
+            }
+            if b == d && b != a && d != c {
+                e[3] = d;
+            }
+            let (ox, oy) = (x * 2, y * 2);
+            out[oy * width * 2 + ox] = e[0];
+            out[oy * width * 2 + ox + 1] = e[1];
+            out[(oy + 1) * width * 2 + ox] = e[2];
+            out[(oy + 1) * width * 2 + ox + 1] = e[3];
+        }
+    }
+    out
+}
+
+/// Triples the size and darkens the right column and bottom row of every pixel
+fn lcd_grid(src: &[Colour], width: usize, height: usize) -> Vec<Colour> {
+    let darken = |(r, g, b): Colour| {
+        let dark = |v: u8| (v as u16 * 3 / 4) as u8;
+        (dark(r), dark(g), dark(b))
+    };
+    let mut out = Vec::with_capacity(width * height * 9);
+    for y in 0..height * 3 {
+        for x in 0..width * 3 {
+            let colour = src[(y / 3) * width + x / 3];
+            if x % 3 == 2 || y % 3 == 2 {
+                out.push(darken(colour));
+            } else {
+                out.push(colour);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::GREY;
+    use crate::BLANK_SCREEN;
+
+    #[test]
+    fn test_scale2x_diagonal() {
+        let (w, k) = ((255, 255, 255), (0, 0, 0));
+        // a black diagonal from the top right to the bottom left
+        let src = vec![w, k, k, w];
+        let out = scale2x(&src, 2, 2);
+        // white pixels get the corner facing the line filled in
+        assert_eq!(&out[0..4], &[w, w, k, k]);
+        assert_eq!(&out[4..8], &[w, k, w, k]);
+    }
+
+    #[test]
+    fn test_ghosting_blends_frames() {
+        let mut filter = FrameFilter::new(Filter::Nearest, true, 160, 144);
+        let mut screen = BLANK_SCREEN;
+        assert_eq!(&filter.apply(&screen, &GREY)[0..3], &[255, 255, 255]);
+        screen[0][0] = 3;
+        assert_eq!(&filter.apply(&screen, &GREY)[0..3], &[127, 127, 127]);
+        assert_eq!(filter.size(), (160, 144));
+    }
+}
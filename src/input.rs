@@ -1,38 +1,346 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use sdl2::controller::{Axis, Button, GameController};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use sdl2::GameControllerSubsystem;
+use serde::{Deserialize, Serialize};
+
+use crate::joypad::Key;
 
-use crate::joypad;
-use crate::joypad::{Key};
+// How far a stick has to be pushed before it counts as a direction
+const AXIS_THRESHOLD: i16 = 16384;
 
 #[derive(Debug, PartialEq)]
 pub enum Control {
-    KeyUp(joypad::Key),
-    KeyDown(joypad::Key),
+    KeyUp(Key),
+    KeyDown(Key),
+    /// Keys of the joypads after the first, counting from 0, for SGB multiplayer
+    PlayerKeyUp(usize, Key),
+    PlayerKeyDown(usize, Key),
+    HotkeyUp(Hotkey),
+    HotkeyDown(Hotkey),
+    Quit,
+}
+
+/// Emulator functions that can be bound next to the joypad
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Hotkey {
+    Pause,
+    FastForward,
+    SlowMotion,
+    FrameAdvance,
+    SaveState,
+    LoadState,
+    NextSlot,
+    PreviousSlot,
+    Screenshot,
+    Record,
+    Fullscreen,
+    VramViewer,
+    Cheats,
+    /// Start a ram search for 8 bit values over all of ram
+    RamSearch,
+    /// Keep the ram search candidates that compare so to the last search step
+    RamSearchEqual,
+    RamSearchChanged,
+    RamSearchIncreased,
+    RamSearchDecreased,
+    Reset,
     Quit,
 }
 
-fn keycode_to_key(keycode: Keycode) -> Option<joypad::Key> {
-    match keycode {
-        Keycode::A => Some(Key::A),
-        Keycode::B => Some(joypad::Key::B),
-        Keycode::Return => Some(joypad::Key::Select),
-        Keycode::Space => Some(joypad::Key::Start),
-        Keycode::Up => Some(joypad::Key::Up),
-        Keycode::Down => Some(joypad::Key::Down),
-        Keycode::Left => Some(joypad::Key::Left),
-        Keycode::Right => Some(joypad::Key::Right),
-        _ => None,
-    }
-}
-
-pub fn from_sdl2_event(e: Event) -> Option<Control> {
-    match e {
-        Event::KeyUp { keycode, .. } => match keycode {
-            Some(Keycode::Escape) => Some(Control::Quit),
-            Some(k) => keycode_to_key(k).map(|k| Control::KeyUp(k)),
-            None => None,
-        },
-        Event::KeyDown { keycode, .. } => keycode_to_key(keycode?).map(|k| Control::KeyDown(k)),
-        _ => None,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Action {
+    Joypad(Key),
+    Hotkey(Hotkey),
+    /// A key of SGB joypad 1-4, e.g. `{ player = 2, key = "A" }`
+    Player {
+        player: usize,
+        key: Key,
+    },
+}
+
+impl Action {
+    fn pressed(self) -> Control {
+        match self {
+            Action::Joypad(key) => Control::KeyDown(key),
+            Action::Hotkey(Hotkey::Quit) => Control::Quit,
+            Action::Hotkey(hotkey) => Control::HotkeyDown(hotkey),
+            Action::Player { player, key } if player > 1 => Control::PlayerKeyDown(player - 1, key),
+            Action::Player { key, .. } => Control::KeyDown(key),
+        }
+    }
+
+    fn released(self) -> Option<Control> {
+        match self {
+            Action::Joypad(key) => Some(Control::KeyUp(key)),
+            Action::Hotkey(Hotkey::Quit) => None,
+            Action::Hotkey(hotkey) => Some(Control::HotkeyUp(hotkey)),
+            Action::Player { player, key } if player > 1 => {
+                Some(Control::PlayerKeyUp(player - 1, key))
+            }
+            Action::Player { key, .. } => Some(Control::KeyUp(key)),
+        }
+    }
+}
+
+/// Bindings as written in the config file, using SDL's names for keys,
+/// controller buttons and axes. Axes are bound per direction, e.g. `leftx-`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BindingsConfig {
+    pub keyboard: BTreeMap<String, Action>,
+    pub controller_buttons: BTreeMap<String, Action>,
+    pub controller_axes: BTreeMap<String, Action>,
+}
+
+impl Default for BindingsConfig {
+    fn default() -> Self {
+        use Action::*;
+        let keyboard = vec![
+            ("A", Joypad(Key::A)),
+            ("B", Joypad(Key::B)),
+            ("Return", Joypad(Key::Select)),
+            ("Space", Joypad(Key::Start)),
+            ("Up", Joypad(Key::Up)),
+            ("Down", Joypad(Key::Down)),
+            ("Left", Joypad(Key::Left)),
+            ("Right", Joypad(Key::Right)),
+            ("Escape", Hotkey(self::Hotkey::Quit)),
+            ("P", Hotkey(self::Hotkey::Pause)),
+            ("Tab", Hotkey(self::Hotkey::FastForward)),
+            ("Left Shift", Hotkey(self::Hotkey::SlowMotion)),
+            ("N", Hotkey(self::Hotkey::FrameAdvance)),
+            ("F1", Hotkey(self::Hotkey::Reset)),
+            ("F2", Hotkey(self::Hotkey::VramViewer)),
+            ("F3", Hotkey(self::Hotkey::Cheats)),
+            ("F4", Hotkey(self::Hotkey::RamSearch)),
+            ("1", Hotkey(self::Hotkey::RamSearchEqual)),
+            ("2", Hotkey(self::Hotkey::RamSearchChanged)),
+            ("3", Hotkey(self::Hotkey::RamSearchIncreased)),
+            ("4", Hotkey(self::Hotkey::RamSearchDecreased)),
+            ("F5", Hotkey(self::Hotkey::SaveState)),
+            ("F6", Hotkey(self::Hotkey::PreviousSlot)),
+            ("F7", Hotkey(self::Hotkey::NextSlot)),
+            ("F8", Hotkey(self::Hotkey::LoadState)),
+            ("F9", Hotkey(self::Hotkey::Record)),
+            ("F11", Hotkey(self::Hotkey::Fullscreen)),
+            ("F12", Hotkey(self::Hotkey::Screenshot)),
+        ];
+        // buttons by position, the right face button is A like on the Game Boy
+        let controller_buttons = vec![
+            ("b", Joypad(Key::A)),
+            ("a", Joypad(Key::B)),
+            ("back", Joypad(Key::Select)),
+            ("start", Joypad(Key::Start)),
+            ("dpup", Joypad(Key::Up)),
+            ("dpdown", Joypad(Key::Down)),
+            ("dpleft", Joypad(Key::Left)),
+            ("dpright", Joypad(Key::Right)),
+            ("guide", Hotkey(self::Hotkey::Pause)),
+            ("rightshoulder", Hotkey(self::Hotkey::FastForward)),
+        ];
+        let controller_axes = vec![
+            ("leftx-", Joypad(Key::Left)),
+            ("leftx+", Joypad(Key::Right)),
+            ("lefty-", Joypad(Key::Up)),
+            ("lefty+", Joypad(Key::Down)),
+        ];
+        let to_map = |bindings: Vec<(&str, Action)>| {
+            bindings
+                .into_iter()
+                .map(|(name, action)| (name.to_string(), action))
+                .collect()
+        };
+        BindingsConfig {
+            keyboard: to_map(keyboard),
+            controller_buttons: to_map(controller_buttons),
+            controller_axes: to_map(controller_axes),
+        }
+    }
+}
+
+impl BindingsConfig {
+    /// Reads a json file, or toml for any other extension
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("unable to open '{}'", path.display()))?;
+        let config = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&text)?,
+            _ => toml::from_str(&text)?,
+        };
+        Ok(config)
+    }
+}
+
+/// Turns SDL keyboard and controller events into `Control`s
+pub struct Bindings {
+    keys: HashMap<Keycode, Action>,
+    buttons: HashMap<Button, Action>,
+    axes: HashMap<(Axis, bool), Action>,
+    pushed_axes: HashMap<(Axis, bool), bool>,
+}
+
+impl Bindings {
+    pub fn new(config: &BindingsConfig) -> Result<Self> {
+        let mut keys = HashMap::new();
+        for (name, &action) in &config.keyboard {
+            let keycode =
+                Keycode::from_name(name).ok_or_else(|| anyhow!("unknown key '{}'", name))?;
+            keys.insert(keycode, action);
+        }
+        let mut buttons = HashMap::new();
+        for (name, &action) in &config.controller_buttons {
+            let button = Button::from_string(name)
+                .ok_or_else(|| anyhow!("unknown controller button '{}'", name))?;
+            buttons.insert(button, action);
+        }
+        let mut axes = HashMap::new();
+        for (name, &action) in &config.controller_axes {
+            axes.insert(parse_axis(name)?, action);
+        }
+        Ok(Bindings {
+            keys,
+            buttons,
+            axes,
+            pushed_axes: HashMap::new(),
+        })
+    }
+
+    pub fn translate(&mut self, event: &Event) -> Vec<Control> {
+        match *event {
+            Event::Quit { .. } => vec![Control::Quit],
+            Event::KeyDown {
+                keycode: Some(keycode),
+                repeat: false,
+                ..
+            } => self
+                .keys
+                .get(&keycode)
+                .map(|a| a.pressed())
+                .into_iter()
+                .collect(),
+            Event::KeyUp {
+                keycode: Some(keycode),
+                ..
+            } => self
+                .keys
+                .get(&keycode)
+                .and_then(|a| a.released())
+                .into_iter()
+                .collect(),
+            Event::ControllerButtonDown { button, .. } => self
+                .buttons
+                .get(&button)
+                .map(|a| a.pressed())
+                .into_iter()
+                .collect(),
+            Event::ControllerButtonUp { button, .. } => self
+                .buttons
+                .get(&button)
+                .and_then(|a| a.released())
+                .into_iter()
+                .collect(),
+            Event::ControllerAxisMotion { axis, value, .. } => {
+                let mut controls = Vec::new();
+                for &positive in &[false, true] {
+                    let pushed = if positive {
+                        value > AXIS_THRESHOLD
+                    } else {
+                        value < -AXIS_THRESHOLD
+                    };
+                    let was_pushed = self.pushed_axes.insert((axis, positive), pushed);
+                    if was_pushed.unwrap_or(false) == pushed {
+                        continue;
+                    }
+                    if let Some(&action) = self.axes.get(&(axis, positive)) {
+                        if pushed {
+                            controls.push(action.pressed());
+                        } else {
+                            controls.extend(action.released());
+                        }
+                    }
+                }
+                controls
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn parse_axis(name: &str) -> Result<(Axis, bool)> {
+    let (axis, positive) = if let Some(axis) = name.strip_suffix('+') {
+        (axis, true)
+    } else if let Some(axis) = name.strip_suffix('-') {
+        (axis, false)
+    } else {
+        return Err(anyhow!(
+            "controller axis '{}' needs a + or - direction",
+            name
+        ));
+    };
+    let axis =
+        Axis::from_string(axis).ok_or_else(|| anyhow!("unknown controller axis '{}'", name))?;
+    Ok((axis, positive))
+}
+
+/// Keeps every connected game controller open so its events are delivered
+pub struct Controllers {
+    subsystem: GameControllerSubsystem,
+    open: Vec<GameController>,
+}
+
+impl Controllers {
+    pub fn new(sdl_context: &sdl2::Sdl) -> Result<Self> {
+        let subsystem = sdl_context.game_controller().map_err(|s| anyhow!(s))?;
+        Ok(Controllers {
+            subsystem,
+            open: Vec::new(),
+        })
+    }
+
+    /// SDL reports controllers present at startup as added as well
+    pub fn handle_event(&mut self, event: &Event) {
+        match *event {
+            Event::ControllerDeviceAdded { which, .. } => {
+                if let Ok(controller) = self.subsystem.open(which) {
+                    self.open.push(controller);
+                }
+            }
+            Event::ControllerDeviceRemoved { which, .. } => {
+                self.open.retain(|c| c.instance_id() != which);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bindings_config_round_trip() {
+        let config = BindingsConfig::default();
+        let text = toml::to_string(&config).unwrap();
+        assert_eq!(toml::from_str::<BindingsConfig>(&text).unwrap(), config);
+
+        let partial: BindingsConfig =
+            toml::from_str("[keyboard]\nZ = \"A\"\nF9 = \"Screenshot\"\n").unwrap();
+        assert_eq!(partial.keyboard["Z"], Action::Joypad(Key::A));
+        assert_eq!(partial.keyboard["F9"], Action::Hotkey(Hotkey::Screenshot));
+        assert_eq!(partial.controller_buttons, config.controller_buttons);
+
+        let player: BindingsConfig =
+            toml::from_str("[keyboard]\nI = { player = 2, key = \"Up\" }\n").unwrap();
+        assert_eq!(
+            player.keyboard["I"].pressed(),
+            Control::PlayerKeyDown(1, Key::Up)
+        );
     }
 }
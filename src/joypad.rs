@@ -1,8 +1,11 @@
 use crate::input::Control;
-use crate::{InputReceiver, Interrupt};
+use crate::savestate::unconnected_receiver;
+use crate::{InputReceiver, Interrupt, FRAME_CYCLES};
+use serde::{Deserialize, Serialize};
+use std::mem;
 use std::sync::mpsc::TryRecvError;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Key {
     Down,
     Up,
@@ -14,12 +17,25 @@ pub enum Key {
     B,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Joypad {
-    select_map: u8, // bitmap 0b10 = directions, 0b01 = buttons
-    directions: u8, // bitmap show not pressed
-    buttons: u8,
+    select_map: u8,      // bitmap 0b10 = buttons, 0b01 = directions, selected when 0
+    directions: [u8; 4], // bitmap show not pressed, one per joypad
+    buttons: [u8; 4],
+    held_directions: [u8; 4],
+    held_buttons: [u8; 4],
+    /// Joypad read from, advanced by the SGB with more than one player
+    player: usize,
+    players: usize,
+    #[serde(skip, default = "unconnected_receiver")]
     input_receiver: InputReceiver,
     prev_state: u8,
+    frame_cycle: u32,
+    /// Clock cycles after the start of VBlank at which new input is latched,
+    /// so it reaches the game at the same point of every frame
+    pub latch_cycle: u32,
+    /// Never report left+right or up+down at the same time, neither is then pressed
+    pub filter_opposite: bool,
 }
 
 const UP_MASK: u8 = 0b1000;
@@ -35,71 +51,134 @@ impl Joypad {
     pub fn new(input_receiver: InputReceiver) -> Self {
         Joypad {
             select_map: 0x00,
-            directions: 0b1111,
-            buttons: 0b1111,
+            directions: [0b1111; 4],
+            buttons: [0b1111; 4],
+            held_directions: [0b1111; 4],
+            held_buttons: [0b1111; 4],
+            player: 0,
+            players: 1,
             input_receiver,
-            prev_state: 0,
+            prev_state: 0b1111,
+            frame_cycle: 0,
+            latch_cycle: 0,
+            filter_opposite: false,
         }
     }
 
+    /// P10-P13 are pulled low by pressed keys on every selected line. With
+    /// neither line selected they give the SGB joypad id, 0xF for player 1.
     pub fn read_word(&self) -> u8 {
-        let mut res = 0b11000000;
-        res |= self.select_map << 4;
+        let mut res = 0b11000000 | self.select_map << 4 | 0b1111;
         if self.select_map & 0b10 == 0 {
-            res |= self.buttons;
+            res &= 0b11110000 | self.buttons[self.player];
         }
         if self.select_map & 0b01 == 0 {
-            res |= self.directions;
+            res &= 0b11110000 | self.directions[self.player];
+        }
+        if self.select_map == 0b11 {
+            res -= self.player as u8;
         }
         res
     }
 
     pub fn write_word(&mut self, v: u8) {
+        let previous = self.select_map;
         self.select_map = (v & 0b00110000) >> 4;
+        // the SGB moves on to the next joypad when P15 is released
+        if self.players > 1 && previous & 0b10 == 0 && self.select_map == 0b11 {
+            self.player = (self.player + 1) % self.players;
+        }
     }
 
-    pub fn key_up(&mut self, key: Key) {
-        use Key::*;
-        match key {
-            Up => self.directions |= UP_MASK,
-            Down => self.directions |= DOWN_MASK,
-            Left => self.directions |= LEFT_MASK,
-            Right => self.directions |= RIGHT_MASK,
-            Start => self.buttons |= START_MASK,
-            Select => self.buttons |= SELECT_MASK,
-            B => self.buttons |= B_MASK,
-            A => self.buttons |= A_MASK,
+    /// Takes over the input channel, the keys held right now and the input settings of `old`
+    pub(crate) fn keep_connections(&mut self, old: &mut Joypad) {
+        mem::swap(&mut self.input_receiver, &mut old.input_receiver);
+        self.held_directions = old.held_directions;
+        self.held_buttons = old.held_buttons;
+        self.latch_cycle = old.latch_cycle;
+        self.filter_opposite = old.filter_opposite;
+    }
+
+    /// Number of joypads read in turn, as requested from the SGB by MLT_REQ
+    pub fn set_players(&mut self, players: usize) {
+        if players != self.players {
+            self.players = players.clamp(1, 4);
+            self.player = 0;
         }
     }
 
-    pub fn key_down(&mut self, key: Key) {
+    /// Keys pressed and released only reach the game once they are latched
+    pub fn key_up(&mut self, player: usize, key: Key) {
+        let (directions, buttons) = self.key_masks(key);
+        self.held_directions[player] |= directions;
+        self.held_buttons[player] |= buttons;
+    }
+
+    pub fn key_down(&mut self, player: usize, key: Key) {
+        let (directions, buttons) = self.key_masks(key);
+        self.held_directions[player] &= !directions;
+        self.held_buttons[player] &= !buttons;
+    }
+
+    fn key_masks(&self, key: Key) -> (u8, u8) {
         use Key::*;
         match key {
-            Up => self.directions &= !UP_MASK,
-            Down => self.directions &= !DOWN_MASK,
-            Left => self.directions &= !LEFT_MASK,
-            Right => self.directions &= !RIGHT_MASK,
-            Start => self.buttons &= !START_MASK,
-            Select => self.buttons &= !SELECT_MASK,
-            B => self.buttons &= !B_MASK,
-            A => self.buttons &= !A_MASK,
+            Up => (UP_MASK, 0),
+            Down => (DOWN_MASK, 0),
+            Left => (LEFT_MASK, 0),
+            Right => (RIGHT_MASK, 0),
+            Start => (0, START_MASK),
+            Select => (0, SELECT_MASK),
+            B => (0, B_MASK),
+            A => (0, A_MASK),
         }
     }
 
+    /// Applies everything received since the last latch
     pub fn process_inputs(&mut self) {
-        let res = self.input_receiver.try_recv();
-        match res {
-            Ok(control) => match control {
-                Control::KeyUp(k) => self.key_up(k),
-                Control::KeyDown(k) => self.key_down(k),
-                _ => {}
-            },
-            Err(TryRecvError::Empty) => {}
-            Err(TryRecvError::Disconnected) => panic!("Disconnected channel"),
+        loop {
+            match self.input_receiver.try_recv() {
+                Ok(Control::KeyUp(k)) => self.key_up(0, k),
+                Ok(Control::KeyDown(k)) => self.key_down(0, k),
+                Ok(Control::PlayerKeyUp(p, k)) if p < 4 => self.key_up(p, k),
+                Ok(Control::PlayerKeyDown(p, k)) if p < 4 => self.key_down(p, k),
+                Ok(_) => {}
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => panic!("Disconnected channel"),
+            }
         }
+        self.latch();
     }
 
-    pub fn tick(&mut self) -> u8 {
+    fn latch(&mut self) {
+        for (latched, &held) in self.directions.iter_mut().zip(&self.held_directions) {
+            let mut directions = held;
+            if self.filter_opposite {
+                if directions & (LEFT_MASK | RIGHT_MASK) == 0 {
+                    directions |= LEFT_MASK | RIGHT_MASK;
+                }
+                if directions & (UP_MASK | DOWN_MASK) == 0 {
+                    directions |= UP_MASK | DOWN_MASK;
+                }
+            }
+            *latched = directions;
+        }
+        self.buttons = self.held_buttons;
+    }
+
+    /// Advances one M-cycle, `frame_start` is set when VBlank begins. Input is
+    /// latched once per frame, with the lcd off frames are counted out instead.
+    pub fn tick(&mut self, frame_start: bool) -> u8 {
+        self.frame_cycle = if frame_start {
+            0
+        } else {
+            (self.frame_cycle + 4) % FRAME_CYCLES
+        };
+        if self.frame_cycle == (self.latch_cycle % FRAME_CYCLES) & !3 {
+            self.process_inputs();
+        }
+        // any of P10-P13 going low requests an interrupt, whether a key was
+        // pressed or a line with a key already held got selected
         let pre = self.prev_state & 0b1111;
         self.prev_state = self.read_word();
         let new = self.prev_state & 0b1111;
@@ -110,3 +189,74 @@ impl Joypad {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn tick_frame(joypad: &mut Joypad) -> u8 {
+        let mut ints = joypad.tick(true);
+        for _ in 1..FRAME_CYCLES / 4 {
+            ints |= joypad.tick(false);
+        }
+        ints
+    }
+
+    #[test]
+    fn test_input_latched_per_frame() {
+        let (tx, rx) = mpsc::channel();
+        let mut joypad = Joypad::new(rx);
+        joypad.latch_cycle = 100 * 4;
+        joypad.write_word(0b0010_0000);
+        tick_frame(&mut joypad);
+        tx.send(Control::KeyDown(Key::Right)).unwrap();
+        tx.send(Control::KeyDown(Key::A)).unwrap();
+        joypad.tick(true);
+        for _ in 1..100 {
+            assert_eq!(joypad.tick(false), Interrupt::NoInterrupt as u8);
+            assert_eq!(joypad.read_word(), 0b1110_1111);
+        }
+        assert_eq!(joypad.tick(false), Interrupt::JOYPAD as u8);
+        assert_eq!(joypad.read_word(), 0b1110_1110);
+        // selecting the buttons pulls P10 low through the held A
+        joypad.write_word(0b0011_0000);
+        tick_frame(&mut joypad);
+        joypad.write_word(0b0001_0000);
+        assert_eq!(joypad.tick(false), Interrupt::JOYPAD as u8);
+    }
+
+    #[test]
+    fn test_filter_opposite_directions() {
+        let (tx, rx) = mpsc::channel();
+        let mut joypad = Joypad::new(rx);
+        joypad.filter_opposite = true;
+        joypad.write_word(0b0010_0000);
+        tx.send(Control::KeyDown(Key::Left)).unwrap();
+        tx.send(Control::KeyDown(Key::Right)).unwrap();
+        tx.send(Control::KeyDown(Key::Up)).unwrap();
+        tick_frame(&mut joypad);
+        assert_eq!(joypad.read_word() & 0b1111, 0b0111);
+        tx.send(Control::KeyUp(Key::Right)).unwrap();
+        tick_frame(&mut joypad);
+        assert_eq!(joypad.read_word() & 0b1111, 0b0101);
+    }
+
+    #[test]
+    fn test_sgb_multiplayer_ids() {
+        let (tx, rx) = mpsc::channel();
+        let mut joypad = Joypad::new(rx);
+        joypad.set_players(2);
+        tx.send(Control::PlayerKeyDown(1, Key::Start)).unwrap();
+        tick_frame(&mut joypad);
+        // read the buttons, then release P15 to get the id of the next joypad
+        joypad.write_word(0x10);
+        assert_eq!(joypad.read_word() & 0b1111, 0b1111);
+        joypad.write_word(0x30);
+        assert_eq!(joypad.read_word() & 0b1111, 0xE);
+        joypad.write_word(0x10);
+        assert_eq!(joypad.read_word() & 0b1111, 0b0111);
+        joypad.write_word(0x30);
+        assert_eq!(joypad.read_word() & 0b1111, 0xF);
+    }
+}
@@ -1,26 +1,49 @@
 use std::fs;
+use std::io::BufWriter;
+use std::path::Path;
 use std::sync::mpsc::{Receiver, SyncSender};
 
+use anyhow::{bail, Result};
+
 use crate::input::Control;
+use crate::palette::{Palette, LAYER_OBJ1};
 
+pub mod cheats;
 pub mod cpu;
 pub mod display;
+pub mod dma;
+pub mod filter;
 pub mod input;
 pub mod joypad;
+pub mod link;
 pub mod mbc;
+pub mod memory;
 pub mod mmu;
+pub mod model;
+pub mod movie;
+pub mod pacing;
+pub mod palette;
 pub mod ppu;
+pub mod printer;
+pub mod recorder;
 pub mod registers;
+pub mod savestate;
 pub mod serial;
+pub mod sgb;
 pub mod timer;
+pub mod vram;
 
 pub type Pixel = u8;
+/// Shades of the frame, with the palette layer above the shade as in `palette::LAYER_*`
 pub type ScreenBuffer = [[u8; 160]; 144];
 pub const BLANK_SCREEN: ScreenBuffer = [[0; 160]; 144];
+/// Frames are dropped rather than waited on while the channel is full
 pub type ScreenSender = SyncSender<[[Pixel; 160]; 144]>;
 pub type InputReceiver = Receiver<Control>;
 
 pub const CPU_CLOCK: u32 = 4194304;
+// Clock cycles between two VBlanks
+pub const FRAME_CYCLES: u32 = 70224;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Interrupt {
@@ -45,3 +68,36 @@ pub fn save_screen_buffer(pixels: &ScreenBuffer, path: String) {
     let buffer = fs::File::create(path);
     serde_json::to_writer(buffer.unwrap(), &pixs).unwrap();
 }
+
+/// Reads a screen buffer written by `save_screen_buffer`
+pub fn load_screen_buffer(path: &Path) -> Result<ScreenBuffer> {
+    let pixels: Vec<u8> = serde_json::from_reader(fs::File::open(path)?)?;
+    if pixels.len() != 160 * 144 {
+        bail!("expected {} pixels, found {}", 160 * 144, pixels.len());
+    }
+    if pixels.iter().any(|&pixel| pixel > (LAYER_OBJ1 | 3)) {
+        bail!("pixels go from 0 to {}", LAYER_OBJ1 | 3);
+    }
+    let mut screen = BLANK_SCREEN;
+    for (row, line) in screen.iter_mut().zip(pixels.chunks(160)) {
+        row.copy_from_slice(line);
+    }
+    Ok(screen)
+}
+
+/// Writes the frame buffer as an rgb png, no display needed
+pub fn save_screen_png(pixels: &ScreenBuffer, palette: &Palette, path: &Path) -> Result<()> {
+    let file = BufWriter::new(fs::File::create(path)?);
+    let mut encoder = png::Encoder::new(file, 160, 144);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let data: Vec<u8> = screen_buffer_to_vec(pixels)
+        .into_iter()
+        .flat_map(|pixel| {
+            let (r, g, b) = palette.colour(pixel);
+            vec![r, g, b]
+        })
+        .collect();
+    encoder.write_header()?.write_image_data(&data)?;
+    Ok(())
+}
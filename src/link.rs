@@ -0,0 +1,202 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+
+use anyhow::Result;
+
+use crate::serial::SerialPeer;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Message {
+    Transfer(u8),
+    Reply(u8),
+}
+
+impl Message {
+    fn to_bytes(self) -> [u8; 2] {
+        match self {
+            Message::Transfer(v) => [0, v],
+            Message::Reply(v) => [1, v],
+        }
+    }
+
+    fn from_bytes(bytes: [u8; 2]) -> Self {
+        match bytes[0] {
+            0 => Message::Transfer(bytes[1]),
+            _ => Message::Reply(bytes[1]),
+        }
+    }
+}
+
+/// Link cable between two emulator instances running in the same process.
+/// Neither side ever waits on the other, the clock master keeps asking for the
+/// answer and `Serial` gives up after a couple of emulated frames.
+pub struct ChannelPeer {
+    tx: Sender<Message>,
+    rx: Receiver<Message>,
+}
+
+impl ChannelPeer {
+    pub fn pair() -> (ChannelPeer, ChannelPeer) {
+        let (tx_a, rx_a) = channel();
+        let (tx_b, rx_b) = channel();
+        (
+            ChannelPeer { tx: tx_a, rx: rx_b },
+            ChannelPeer { tx: tx_b, rx: rx_a },
+        )
+    }
+}
+
+impl SerialPeer for ChannelPeer {
+    fn transfer(&mut self, out: u8) -> Option<u8> {
+        // answers that are already here belong to transfers that timed out
+        while let Ok(message) = self.rx.try_recv() {
+            if let Message::Transfer(_) = message {
+                let _ = self.tx.send(Message::Reply(0xFF));
+            }
+        }
+        if self.tx.send(Message::Transfer(out)).is_err() {
+            return Some(0xFF);
+        }
+        self.reply()
+    }
+
+    fn reply(&mut self) -> Option<u8> {
+        loop {
+            match self.rx.try_recv() {
+                Ok(Message::Reply(v)) => return Some(v),
+                // both sides are clocking, neither sees the other's data
+                Ok(Message::Transfer(_)) => {
+                    let _ = self.tx.send(Message::Reply(0xFF));
+                }
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Disconnected) => return Some(0xFF),
+            }
+        }
+    }
+
+    fn poll_transfer(&mut self, out: u8) -> Option<u8> {
+        loop {
+            match self.rx.try_recv() {
+                Ok(Message::Transfer(v)) => {
+                    let _ = self.tx.send(Message::Reply(out));
+                    return Some(v);
+                }
+                // late answer to a transfer that already timed out
+                Ok(Message::Reply(_)) => {}
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => return None,
+            }
+        }
+    }
+}
+
+/// Link cable to another instance over a tcp connection. The socket is never
+/// waited on, see `ChannelPeer`.
+pub struct TcpPeer {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl TcpPeer {
+    pub fn connect(adr: impl ToSocketAddrs) -> Result<Self> {
+        Self::from_stream(TcpStream::connect(adr)?)
+    }
+
+    /// Blocks until the other instance connects
+    pub fn listen(adr: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(adr)?;
+        let (stream, _) = listener.accept()?;
+        Self::from_stream(stream)
+    }
+
+    fn from_stream(stream: TcpStream) -> Result<Self> {
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        Ok(TcpPeer {
+            stream,
+            buffer: Vec::with_capacity(2),
+        })
+    }
+
+    fn send(&mut self, message: Message) -> bool {
+        self.stream.write_all(&message.to_bytes()).is_ok()
+    }
+
+    /// Reads one message if it has fully arrived
+    fn receive(&mut self) -> Option<Message> {
+        while self.buffer.len() < 2 {
+            let mut bytes = [0; 2];
+            let wanted = 2 - self.buffer.len();
+            match self.stream.read(&mut bytes[..wanted]) {
+                Ok(0) => return None,
+                Ok(n) => self.buffer.extend_from_slice(&bytes[..n]),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return None,
+            }
+        }
+        let message = Message::from_bytes([self.buffer[0], self.buffer[1]]);
+        self.buffer.clear();
+        Some(message)
+    }
+}
+
+impl SerialPeer for TcpPeer {
+    fn transfer(&mut self, out: u8) -> Option<u8> {
+        // answers that are already here belong to transfers that timed out
+        while let Some(message) = self.receive() {
+            if let Message::Transfer(_) = message {
+                self.send(Message::Reply(0xFF));
+            }
+        }
+        if !self.send(Message::Transfer(out)) {
+            return Some(0xFF);
+        }
+        self.reply()
+    }
+
+    fn reply(&mut self) -> Option<u8> {
+        loop {
+            match self.receive()? {
+                Message::Reply(v) => return Some(v),
+                Message::Transfer(_) => {
+                    self.send(Message::Reply(0xFF));
+                }
+            }
+        }
+    }
+
+    fn poll_transfer(&mut self, out: u8) -> Option<u8> {
+        loop {
+            match self.receive()? {
+                Message::Transfer(v) => {
+                    self.send(Message::Reply(out));
+                    return Some(v);
+                }
+                Message::Reply(_) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_channel_peers_exchange_bytes() {
+        let (mut master, mut slave) = ChannelPeer::pair();
+        let handle = thread::spawn(move || loop {
+            if let Some(v) = slave.poll_transfer(0x55) {
+                return v;
+            }
+        });
+        let reply = master.transfer(0xAA).or_else(|| loop {
+            if let Some(v) = master.reply() {
+                break Some(v);
+            }
+        });
+        assert_eq!(reply, Some(0x55));
+        assert_eq!(handle.join().unwrap(), 0xAA);
+    }
+}
@@ -1,3 +1,5 @@
+use anyhow::{bail, Result};
+
 use crate::mbc::MBC;
 
 pub struct MBC0 {
@@ -29,4 +31,20 @@ impl MBC for MBC0 {
             _ => panic!("No such adr 0x{:X} in mbc", adr),
         }
     }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.ext_ram.to_vec()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        if state.len() != self.ext_ram.len() {
+            bail!(
+                "expected {} bytes of cartridge ram, found {}",
+                self.ext_ram.len(),
+                state.len()
+            );
+        }
+        self.ext_ram.copy_from_slice(state);
+        Ok(())
+    }
 }
@@ -1,3 +1,159 @@
-//use crate::mbc::MBC;
+use anyhow::{bail, Result};
 
-pub struct MBC1 {}
+use crate::mbc::MBC;
+
+/// Up to 2MB of rom in 16KB banks and 32KB of ram in 8KB banks
+pub struct MBC1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    /// Lower five bits of the rom bank, 0 reads as 1
+    bank1: u8,
+    /// Upper two bits of the rom bank, or the ram bank in mode 1
+    bank2: u8,
+    /// Mode 1 also applies `bank2` to 0x0000-0x3FFF and to ram
+    mode: u8,
+}
+
+impl MBC1 {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Self {
+        MBC1 {
+            rom,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            bank1: 1,
+            bank2: 0,
+            mode: 0,
+        }
+    }
+
+    fn rom_banks(&self) -> usize {
+        (self.rom.len() / 0x4000).max(1)
+    }
+
+    fn rom_bank0(&self) -> usize {
+        let bank = if self.mode == 1 {
+            (self.bank2 as usize) << 5
+        } else {
+            0
+        };
+        bank % self.rom_banks()
+    }
+
+    fn ram_offset(&self, adr: u16) -> usize {
+        (self.ram_bank() * 0x2000 + (adr as usize - 0xA000)) % self.ram.len()
+    }
+}
+
+impl MBC for MBC1 {
+    fn read_word(&self, adr: u16) -> u8 {
+        match adr {
+            0x0000..=0x3FFF => self.rom[self.rom_bank0() * 0x4000 + adr as usize],
+            0x4000..=0x7FFF => self.rom[self.rom_bank() * 0x4000 + (adr as usize - 0x4000)],
+            0xA000..=0xBFFF if self.ram_enabled && !self.ram.is_empty() => {
+                self.ram[self.ram_offset(adr)]
+            }
+            0xA000..=0xBFFF => 0xFF,
+            _ => panic!("No such adr 0x{:X} in mbc", adr),
+        }
+    }
+
+    fn write_word(&mut self, adr: u16, val: u8) {
+        match adr {
+            0x0000..=0x1FFF => self.ram_enabled = val & 0xF == 0xA,
+            0x2000..=0x3FFF => self.bank1 = (val & 0x1F).max(1),
+            0x4000..=0x5FFF => self.bank2 = val & 0b11,
+            0x6000..=0x7FFF => self.mode = val & 1,
+            0xA000..=0xBFFF if self.ram_enabled && !self.ram.is_empty() => {
+                let offset = self.ram_offset(adr);
+                self.ram[offset] = val;
+            }
+            0xA000..=0xBFFF => {}
+            _ => panic!("No such adr 0x{:X} in mbc", adr),
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = self.ram.clone();
+        state.extend(&[self.ram_enabled as u8, self.bank1, self.bank2, self.mode]);
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        if state.len() != self.ram.len() + 4 {
+            bail!(
+                "expected {} bytes of cartridge ram and registers, found {}",
+                self.ram.len() + 4,
+                state.len()
+            );
+        }
+        let (ram, registers) = state.split_at(self.ram.len());
+        self.ram.copy_from_slice(ram);
+        self.ram_enabled = registers[0] != 0;
+        self.bank1 = registers[1];
+        self.bank2 = registers[2];
+        self.mode = registers[3];
+        Ok(())
+    }
+
+    fn rom_bank(&self) -> usize {
+        ((self.bank2 as usize) << 5 | self.bank1 as usize) % self.rom_banks()
+    }
+
+    fn ram_bank(&self) -> usize {
+        match self.mode {
+            1 => self.bank2 as usize % (self.ram.len() / 0x2000).max(1),
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every rom bank starts with its number
+    fn numbered_rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * 0x4000];
+        for bank in 0..banks {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn test_rom_banks() {
+        let mut mbc = MBC1::new(numbered_rom(64), 0);
+        assert_eq!(mbc.read_word(0x4000), 1);
+        mbc.write_word(0x2000, 0);
+        assert_eq!(mbc.read_word(0x4000), 1);
+        mbc.write_word(0x2000, 0x25);
+        mbc.write_word(0x4000, 1);
+        assert_eq!(mbc.rom_bank(), 0x25);
+        assert_eq!(mbc.read_word(0x4000), 0x25);
+        assert_eq!(mbc.read_word(0x0000), 0);
+        mbc.write_word(0x6000, 1);
+        assert_eq!(mbc.read_word(0x0000), 0x20);
+    }
+
+    #[test]
+    fn test_ram_banks() {
+        let mut mbc = MBC1::new(numbered_rom(4), 0x8000);
+        mbc.write_word(0xA000, 1);
+        assert_eq!(mbc.read_word(0xA000), 0xFF);
+        mbc.write_word(0x0000, 0x0A);
+        mbc.write_word(0x6000, 1);
+        mbc.write_word(0x4000, 2);
+        mbc.write_word(0xA000, 0x42);
+        assert_eq!(mbc.ram_bank(), 2);
+        assert_eq!(mbc.read_word(0xA000), 0x42);
+        mbc.write_word(0x4000, 0);
+        assert_eq!(mbc.read_word(0xA000), 0);
+
+        let state = mbc.save_state();
+        let mut restored = MBC1::new(numbered_rom(4), 0x8000);
+        restored.load_state(&state).unwrap();
+        restored.write_word(0x4000, 2);
+        assert_eq!(restored.read_word(0xA000), 0x42);
+    }
+}
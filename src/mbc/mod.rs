@@ -1,11 +1,75 @@
+use anyhow::Result;
+
 pub mod mbc0;
 pub mod mbc1;
 
-pub trait MBC {
+pub trait MBC: Send {
     fn read_word(&self, adr: u16) -> u8;
     fn write_word(&mut self, adr: u16, val: u8);
+
+    /// Cartridge ram and banking registers, as kept in save states
+    fn save_state(&self) -> Vec<u8>;
+    fn load_state(&mut self, state: &[u8]) -> Result<()>;
+
+    /// Rom bank mapped at 0x4000-0x7FFF
+    fn rom_bank(&self) -> usize {
+        1
+    }
+
+    /// Ram bank mapped at 0xA000-0xBFFF
+    fn ram_bank(&self) -> usize {
+        0
+    }
+}
+
+/// Stands in for the cartridge while a save state is restored
+pub(crate) fn unloaded() -> Box<dyn MBC> {
+    load(Vec::new())
 }
 
+/// Picks the mapper from the cartridge type in the header. Mappers that
+/// aren't emulated yet fall back to plain 32KB of rom.
 pub fn load(rom: Vec<u8>) -> Box<dyn MBC> {
-    Box::new(mbc0::MBC0::new(rom))
+    match rom.get(0x147) {
+        Some(0x01) => Box::new(mbc1::MBC1::new(rom, 0)),
+        Some(0x02..=0x03) => {
+            // at most four 8KB banks, whatever the header claims
+            let ram_size = ram_size(rom.get(0x149).copied().unwrap_or(0)).min(0x8000);
+            Box::new(mbc1::MBC1::new(rom, ram_size))
+        }
+        _ => Box::new(mbc0::MBC0::new(rom)),
+    }
+}
+
+/// Cartridge ram from the header's size code
+fn ram_size(code: u8) -> usize {
+    match code {
+        0x01 => 0x800,
+        0x02 => 0x2000,
+        0x03 => 0x8000,
+        0x04 => 0x20000,
+        0x05 => 0x10000,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(cartridge_type: u8, ram_code: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = cartridge_type;
+        rom[0x149] = ram_code;
+        rom
+    }
+
+    #[test]
+    fn test_mbc1_ram_size() {
+        // registers follow the ram in a save state
+        assert_eq!(load(header(0x01, 0x03)).save_state().len(), 4);
+        assert_eq!(load(header(0x02, 0x03)).save_state().len(), 0x8000 + 4);
+        assert_eq!(load(header(0x03, 0x04)).save_state().len(), 0x8000 + 4);
+        assert_eq!(load(header(0x03, 0x01)).save_state().len(), 0x800 + 4);
+    }
 }
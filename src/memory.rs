@@ -0,0 +1,219 @@
+use std::fmt::Write;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+
+// Cartridge ram, work ram and high ram, where game variables live
+const RAM_RANGES: [(u16, u16); 3] = [(0xA000, 0xBFFF), (0xC000, 0xDFFF), (0xFF80, 0xFFFE)];
+
+/// Copy of the whole address space as the cpu sees it, along with the banks
+/// that were mapped at the time
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemorySnapshot {
+    data: Vec<u8>,
+    pub rom_bank: usize,
+    pub ram_bank: usize,
+}
+
+impl MemorySnapshot {
+    /// `data` holds all 0x10000 addresses
+    pub fn new(data: Vec<u8>, rom_bank: usize, ram_bank: usize) -> Self {
+        assert_eq!(data.len(), 0x10000);
+        MemorySnapshot {
+            data,
+            rom_bank,
+            ram_bank,
+        }
+    }
+
+    pub fn read(&self, adr: u16) -> u8 {
+        self.data[adr as usize]
+    }
+
+    /// Little endian for 16 bit values, `None` past the end of memory
+    pub fn value(&self, adr: u16, width: Width) -> Option<i64> {
+        let low = self.read(adr);
+        let value = match width {
+            Width::U8 => low as i64,
+            Width::I8 => low as i8 as i64,
+            Width::U16 | Width::I16 => {
+                let word = u16::from_le_bytes([low, self.read(adr.checked_add(1)?)]);
+                if width == Width::I16 {
+                    word as i16 as i64
+                } else {
+                    word as i64
+                }
+            }
+        };
+        Some(value)
+    }
+
+    /// Bank mapped at `adr`, for the switchable areas of the cartridge
+    pub fn bank(&self, adr: u16) -> Option<usize> {
+        match adr {
+            0x0000..=0x3FFF => Some(0),
+            0x4000..=0x7FFF => Some(self.rom_bank),
+            0xA000..=0xBFFF => Some(self.ram_bank),
+            _ => None,
+        }
+    }
+
+    /// 16 bytes per line, e.g. `C000: 00 01 ...`
+    pub fn hex_dump(&self, start: u16, len: usize) -> String {
+        let end = (start as usize + len).min(self.data.len());
+        let mut out = String::new();
+        for line in (start as usize..end).step_by(16) {
+            let _ = write!(out, "{:04X}:", line);
+            for byte in &self.data[line..(line + 16).min(end)] {
+                let _ = write!(out, " {:02X}", byte);
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Width {
+    U8,
+    I8,
+    U16,
+    I16,
+}
+
+impl FromStr for Width {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "u8" => Ok(Width::U8),
+            "i8" => Ok(Width::I8),
+            "u16" => Ok(Width::U16),
+            "i16" => Ok(Width::I16),
+            _ => Err(anyhow!("unknown width '{}', use u8, i8, u16 or i16", s)),
+        }
+    }
+}
+
+/// How a value has to relate to the one in the previous snapshot to stay a candidate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Equal,
+    Changed,
+    Increased,
+    Decreased,
+    Value(i64),
+}
+
+impl FromStr for Comparison {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "equal" | "eq" => Ok(Comparison::Equal),
+            "changed" | "ne" => Ok(Comparison::Changed),
+            "increased" | "inc" => Ok(Comparison::Increased),
+            "decreased" | "dec" => Ok(Comparison::Decreased),
+            value => {
+                let parsed = match value.strip_prefix("0x") {
+                    Some(hex) => i64::from_str_radix(hex, 16),
+                    None => value.parse(),
+                };
+                parsed
+                    .map(Comparison::Value)
+                    .map_err(|_| anyhow!("unknown comparison '{}'", s))
+            }
+        }
+    }
+}
+
+/// Narrows down the ram addresses holding a value by comparing snapshots
+/// taken while the value changes in game
+pub struct RamSearch {
+    width: Width,
+    previous: MemorySnapshot,
+    candidates: Vec<u16>,
+}
+
+impl RamSearch {
+    /// Every ram address is a candidate to begin with
+    pub fn new(snapshot: MemorySnapshot, width: Width) -> Self {
+        let candidates = RAM_RANGES
+            .iter()
+            .flat_map(|&(start, end)| match width {
+                Width::U16 | Width::I16 => start..end,
+                _ => start..end + 1,
+            })
+            .collect();
+        RamSearch {
+            width,
+            previous: snapshot,
+            candidates,
+        }
+    }
+
+    pub fn filter(&mut self, snapshot: MemorySnapshot, comparison: Comparison) {
+        let (previous, width) = (&self.previous, self.width);
+        self.candidates.retain(|&adr| {
+            let (old, new) = match (previous.value(adr, width), snapshot.value(adr, width)) {
+                (Some(old), Some(new)) => (old, new),
+                _ => return false,
+            };
+            match comparison {
+                Comparison::Equal => new == old,
+                Comparison::Changed => new != old,
+                Comparison::Increased => new > old,
+                Comparison::Decreased => new < old,
+                Comparison::Value(value) => new == value,
+            }
+        });
+        self.previous = snapshot;
+    }
+
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+
+    pub fn width(&self) -> Width {
+        self.width
+    }
+
+    /// The snapshot the last filter compared against
+    pub fn snapshot(&self) -> &MemorySnapshot {
+        &self.previous
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(values: &[(u16, u8)]) -> MemorySnapshot {
+        let mut data = vec![0; 0x10000];
+        for &(adr, v) in values {
+            data[adr as usize] = v;
+        }
+        MemorySnapshot::new(data, 1, 0)
+    }
+
+    #[test]
+    fn test_ram_search() {
+        let mut search = RamSearch::new(snapshot(&[(0xC100, 3)]), Width::U8);
+        assert_eq!(search.candidates().len(), 0x2000 + 0x2000 + 0x7F);
+        search.filter(snapshot(&[(0xC100, 2), (0xC200, 9)]), Comparison::Decreased);
+        assert_eq!(search.candidates(), &[0xC100]);
+        search.filter(snapshot(&[(0xC100, 2)]), Comparison::Equal);
+        search.filter(snapshot(&[(0xC100, 7)]), "7".parse().unwrap());
+        assert_eq!(search.candidates(), &[0xC100]);
+    }
+
+    #[test]
+    fn test_signed_16_bit_values() {
+        let memory = snapshot(&[(0xC000, 0xFE), (0xC001, 0xFF)]);
+        assert_eq!(memory.value(0xC000, Width::I16), Some(-2));
+        assert_eq!(memory.value(0xC000, Width::U16), Some(0xFFFE));
+        assert_eq!(memory.value(0xFFFF, Width::U16), None);
+        assert_eq!(memory.bank(0x4000), Some(1));
+        assert_eq!(memory.hex_dump(0xC000, 2), "C000: FE FF\n");
+    }
+}
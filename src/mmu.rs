@@ -1,41 +1,69 @@
+use std::mem;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::cheats::Cheats;
+use crate::dma::{Bus, OamDma};
 use crate::joypad::Joypad;
+use crate::memory::MemorySnapshot;
+use crate::model::Model;
 use crate::ppu::PPU;
+use crate::savestate;
 use crate::serial::Serial;
+use crate::sgb::Sgb;
 use crate::timer::Timer;
-use crate::{mbc, InputReceiver, ScreenSender};
+use crate::{mbc, InputReceiver, Interrupt, ScreenSender};
 
-const DMA_LENGTH: u16 = 160;
+const DMG_BOOT_ROM_SIZE: usize = 0x100;
+const CGB_BOOT_ROM_SIZE: usize = 0x900;
 
+#[derive(Serialize, Deserialize)]
 pub struct MMU {
+    #[serde(skip, default = "mbc::unloaded")]
     mbc: Box<dyn mbc::MBC>,
+    #[serde(skip)]
+    boot_rom: Option<Vec<u8>>,
+    boot_rom_mapped: bool,
+    #[serde(with = "savestate::bytes")]
     wram: [u8; 8192],
+    #[serde(with = "savestate::bytes")]
     hram: [u8; 128],
+    #[serde(with = "savestate::bytes")]
     iram: [u8; 0x80],
     pub timer: Timer,
     pub interrupt_flags: u8,
     pub interrupt_enable: u8,
-    dma_cycles_left: u16,
-    dma_start_adr: u16,
+    dma: OamDma,
     pub ppu: PPU,
     pub serial: Serial,
     pub joypad: Joypad,
+    pub model: Model,
+    /// Listens to the joypad register for SGB commands when set
+    pub sgb: Option<Sgb>,
+    #[serde(skip)]
+    pub cheats: Cheats,
 }
 
 impl MMU {
     pub fn new(rom: Vec<u8>, screen_sender: ScreenSender, input_receiver: InputReceiver) -> Self {
         MMU {
             mbc: mbc::load(rom),
+            boot_rom: None,
+            boot_rom_mapped: false,
             wram: [0; 8192],
             hram: [0; 128],
             iram: [0; 0x80],
             timer: Timer::new(),
             interrupt_flags: 0,
             interrupt_enable: 0,
-            dma_cycles_left: 0,
-            dma_start_adr: 0,
+            dma: OamDma::new(),
             ppu: PPU::new(screen_sender),
             serial: Serial::new(),
             joypad: Joypad::new(input_receiver),
+            model: Model::default(),
+            sgb: None,
+            cheats: Cheats::new(),
         }
     }
 
@@ -43,32 +71,193 @@ impl MMU {
         self.interrupt_flags & self.interrupt_enable
     }
 
-    fn start_dma(&mut self, high_adr: u8) {
-        let adr = (high_adr as u16) << 8;
-        //        println!("Dma request adr 0x{:X}", adr);
-        self.dma_cycles_left = DMA_LENGTH * 4;
-        self.dma_start_adr = adr;
+    /// Maps a DMG (256 bytes) or CGB (2304 bytes) boot rom over the cartridge
+    /// until 0xFF50 is written.
+    pub fn load_boot_rom(&mut self, boot_rom: Vec<u8>) -> Result<()> {
+        match boot_rom.len() {
+            DMG_BOOT_ROM_SIZE | CGB_BOOT_ROM_SIZE => {
+                self.boot_rom = Some(boot_rom);
+                self.boot_rom_mapped = true;
+                Ok(())
+            }
+            n => bail!("invalid boot rom size {} bytes", n),
+        }
+    }
+
+    pub fn is_boot_rom_mapped(&self) -> bool {
+        self.boot_rom_mapped && self.boot_rom.is_some()
+    }
+
+    fn read_boot_rom(&self, adr: u16) -> Option<u8> {
+        let boot_rom = self.boot_rom.as_ref()?;
+        match adr {
+            // the cgb boot rom leaves the cartridge header visible
+            0x0000..=0x00FF | 0x0200..=0x08FF => boot_rom.get(adr as usize).cloned(),
+            _ => None,
+        }
+    }
+
+    /// Title from the cartridge header
+    pub fn title(&self) -> String {
+        (0x134..0x144)
+            .map(|adr| self.mbc.read_word(adr))
+            .take_while(|&c| c != 0)
+            .map(char::from)
+            .collect()
+    }
+
+    pub(crate) fn cartridge_state(&self) -> Vec<u8> {
+        self.mbc.save_state()
+    }
+
+    pub(crate) fn load_cartridge_state(&mut self, state: &[u8]) -> Result<()> {
+        self.mbc.load_state(state)
+    }
+
+    /// Takes over from `old` what isn't part of the emulated hardware: the
+    /// cartridge, boot rom, cheats, input settings, link cable and the
+    /// channels to the frontend
+    pub(crate) fn keep_connections(&mut self, old: &mut MMU) {
+        mem::swap(&mut self.mbc, &mut old.mbc);
+        mem::swap(&mut self.boot_rom, &mut old.boot_rom);
+        mem::swap(&mut self.cheats, &mut old.cheats);
+        self.ppu.keep_connections(&mut old.ppu);
+        self.joypad.keep_connections(&mut old.joypad);
+        self.serial.keep_connections(&mut old.serial);
+        if let (Some(sgb), Some(old)) = (&mut self.sgb, &mut old.sgb) {
+            sgb.frame_sender = old.frame_sender.take();
+        }
+    }
+
+    /// Sets up fresh hardware of the same model as `old`, with its cartridge
+    pub(crate) fn power_cycled(&mut self, old: &mut MMU) {
+        self.model = old.model;
+        if old.sgb.is_some() {
+            self.sgb = Some(Sgb::new());
+        }
+        self.keep_connections(old);
+        self.boot_rom_mapped = self.boot_rom.is_some();
+    }
+
+    /// Sets up the io registers the boot rom of `self.model` leaves behind
+    pub fn skip_boot(&mut self) {
+        self.write_word(0xFF05, 0);
+        self.write_word(0xFF06, 0);
+        self.write_word(0xFF07, 0);
+        self.write_word(0xFF10, 0x80);
+        self.write_word(0xFF11, 0xBF);
+        self.write_word(0xFF12, 0xF3);
+        self.write_word(0xFF14, 0xBF);
+        self.write_word(0xFF16, 0x3F);
+        self.write_word(0xFF17, 0);
+        self.write_word(0xFF19, 0xBF);
+        self.write_word(0xFF1A, 0x7F);
+        self.write_word(0xFF1B, 0xFF);
+        self.write_word(0xFF1C, 0x9F);
+        self.write_word(0xFF1E, 0xBF);
+        self.write_word(0xFF20, 0xFF);
+        self.write_word(0xFF21, 0);
+        self.write_word(0xFF22, 0);
+        self.write_word(0xFF23, 0xBF);
+        self.write_word(0xFF24, 0x77);
+        self.write_word(0xFF25, 0xF3);
+        self.write_word(0xFF40, 0x91); // LCDC
+        self.write_word(0xFF42, 0); // SCY
+        self.write_word(0xFF43, 0); // SCX
+        self.write_word(0xFF45, 0); // LYC
+        self.write_word(0xFF47, 0xFC); // BGP
+        self.write_word(0xFF4A, 0); // WY
+        self.write_word(0xFF4B, 0); // WX
+        self.write_word(0xFFFF, 0); // IE
+        for &(adr, v) in self.model.io_registers().iter() {
+            self.write_word(adr, v);
+        }
+        self.interrupt_flags = 0xE1;
+        self.dma.reg = if self.model.is_cgb() { 0x00 } else { 0xFF };
+        self.timer.set_internal_div(self.model.div());
+        let (ly, dot) = self.model.ppu_position();
+        self.ppu.set_position(ly, dot);
+    }
+
+    /// Reads as seen by the dma unit, which bypasses ppu access restrictions
+    fn dma_read(&self, adr: u16) -> u8 {
+        match adr {
+            0x8000..=0x9FFF => self.ppu.vram[(adr - 0x8000) as usize],
+            0xC000..=0xFFFF => self.wram[(adr & 0x1FFF) as usize],
+            _ => self.mbc.read_word(adr),
+        }
+    }
+
+    /// Whether a cpu access to `adr` collides with a running oam dma
+    fn dma_conflict(&self, adr: u16) -> bool {
+        match (self.dma.bus(), Bus::of(adr)) {
+            (Some(_), Bus::Oam) => true,
+            (Some(dma_bus), cpu_bus) => dma_bus == cpu_bus,
+            (None, _) => false,
+        }
     }
 
     pub fn read_word(&self, adr: u16) -> u8 {
+        if self.dma_conflict(adr) {
+            // oam is disconnected, other buses see the byte being transferred
+            return match Bus::of(adr) {
+                Bus::Oam => 0xFF,
+                _ => self.dma.last_value,
+            };
+        }
+        self.read_mapped(adr)
+    }
+
+    fn read_mapped(&self, adr: u16) -> u8 {
         match adr {
-            0x0000..=0x7FFF => self.mbc.read_word(adr),
+            0x0000..=0x08FF if self.is_boot_rom_mapped() => self
+                .read_boot_rom(adr)
+                .unwrap_or_else(|| self.read_rom(adr)),
+            0x0000..=0x7FFF => self.read_rom(adr),
             0xA000..=0xBFFF => self.mbc.read_word(adr),
             0xC000..=0xDFFF => self.wram[(adr - 0xC000) as usize],
             0xE000..=0xFDFF => self.wram[(adr - 0xE000) as usize],
             0xFEA0..=0xFEFF => 0x00, // Undocumented
+            0x8000..=0x9FFF | 0xFE00..=0xFE9F => self.ppu.read_word(adr),
+            0xFF00..=0xFF7F => match io_read_mask(adr, self.model.is_cgb()) {
+                Some(mask) => self.read_io(adr) | mask,
+                None => 0xFF,
+            },
+            0xFF80..=0xFFFE => self.hram[(adr - 0xFF80) as usize],
+            0xFFFF => self.interrupt_enable,
+        }
+    }
+
+    /// Cartridge rom as patched by Game Genie codes
+    fn read_rom(&self, adr: u16) -> u8 {
+        self.cheats.patch_rom(adr, self.mbc.read_word(adr))
+    }
+
+    fn read_io(&self, adr: u16) -> u8 {
+        match adr {
             0xFF00 => self.joypad.read_word(),
             0xFF01..=0xFF02 => self.serial.read_word(adr),
             0xFF04..=0xFF07 => self.timer.read_word(adr),
-            0xFF46 => return (self.dma_start_adr >> 8) as u8,
-            0x8000..=0x9FFF | 0xFE00..=0xFE9F | 0xFF40..=0xFF4A => self.ppu.read_word(adr),
-            0xFF01..=0xFF0E | 0xFF10..=0xFF7F => self.iram[(adr - 0xFF00) as usize],
             0xFF0F => self.interrupt_flags,
-            0xFF80..=0xFFFE => self.hram[(adr - 0xFF80) as usize],
-            0xFFFF => self.interrupt_enable,
+            0xFF46 => self.dma.reg,
+            0xFF40..=0xFF4B => self.ppu.read_word(adr),
+            _ => self.iram[(adr - 0xFF00) as usize],
         }
     }
 
+    /// Every address as read without side effects, for debugging. Vram and
+    /// oam are shown even while the ppu keeps the cpu from reading them.
+    pub fn snapshot(&self) -> MemorySnapshot {
+        let data = (0..=0xFFFF)
+            .map(|adr| match adr {
+                0x8000..=0x9FFF => self.ppu.vram[adr as usize - 0x8000],
+                0xFE00..=0xFE9F => self.ppu.oam[adr as usize - 0xFE00],
+                _ => self.read_mapped(adr),
+            })
+            .collect();
+        MemorySnapshot::new(data, self.mbc.rom_bank(), self.mbc.ram_bank())
+    }
+
     pub fn read_dw(&self, adr: u16) -> u16 {
         let h = self.read_word(adr) as u16;
         let l = (self.read_word(adr.wrapping_add(1)) as u16) << 8;
@@ -76,21 +265,43 @@ impl MMU {
     }
 
     pub fn write_word(&mut self, adr: u16, val: u8) {
+        if self.dma_conflict(adr) {
+            return;
+        }
         match adr {
             0x0000..=0x7FFF => self.mbc.write_word(adr, val),
             0xA000..=0xBFFF => self.mbc.write_word(adr, val),
             0xC000..=0xDFFF => self.wram[(adr - 0xC000) as usize] = val,
             0xE000..=0xFDFF => self.wram[(adr - 0xE000) as usize] = val,
             0xFEA0..=0xFEFF => {} //undocumented
-            0xFF00 => self.joypad.write_word(val),
+            0xFF00 => {
+                self.joypad.write_word(val);
+                if let Some(sgb) = &mut self.sgb {
+                    sgb.write_joypad(val);
+                    self.joypad.set_players(sgb.players);
+                }
+            }
             0xFF01..=0xFF02 => self.serial.write_word(adr, val),
             0xFF04..=0xFF07 => self.timer.write_word(adr, val),
-            0xFF46 => self.start_dma(val),
+            0xFF46 => self.dma.start(val),
+            0xFF41 if !self.model.is_cgb() => {
+                self.ppu.stat_write_glitch();
+                self.ppu.write_word(adr, val)
+            }
+            0xFF50 => {
+                if val != 0 {
+                    self.boot_rom_mapped = false;
+                }
+            }
             0xFFFF => self.interrupt_enable = val,
-            0x8000..=0x9FFF | 0xFE00..=0xFE9F | 0xFF40..=0xFF45 | 0xFF47..=0xFF4A => {
+            0x8000..=0x9FFF | 0xFE00..=0xFE9F | 0xFF40..=0xFF45 | 0xFF47..=0xFF4B => {
                 self.ppu.write_word(adr, val)
             }
-            0xFF01..=0xFF0E | 0xFF10..=0xFF7F => self.iram[(adr - 0xFF00) as usize] = val,
+            0xFF01..=0xFF0E | 0xFF10..=0xFF7F => {
+                if io_read_mask(adr, self.model.is_cgb()).is_some() {
+                    self.iram[(adr - 0xFF00) as usize] = val
+                }
+            }
             0xFF0F => self.interrupt_flags = val | 0b11100000,
             0xFF80..=0xFFFE => self.hram[(adr - 0xFF80) as usize] = val,
         }
@@ -102,18 +313,164 @@ impl MMU {
     }
 
     pub fn tick(&mut self) {
-        if self.dma_cycles_left > 0 {
-            let offset = DMA_LENGTH - self.dma_cycles_left as u16 / 4;
-            let source_adr = self.dma_start_adr + offset;
-            let v = self.read_word(source_adr);
-            self.write_word(0xFE00 + offset, v);
-            self.dma_cycles_left -= 4;
+        if let Some((source_adr, offset)) = self.dma.tick() {
+            let v = self.dma_read(source_adr);
+            self.dma.last_value = v;
+            self.ppu.oam[offset as usize] = v;
         }
         let timer_interrupt = self.timer.tick();
         let ppu_ints = self.ppu.tick();
         let serial_ints = self.serial.tick();
-        let joypad_ints = self.joypad.tick();
-        self.joypad.process_inputs();
+        let vblank = ppu_ints & Interrupt::VBLANK as u8 != 0;
+        if let (true, Some(sgb)) = (vblank, &mut self.sgb) {
+            sgb.frame(&self.ppu.screen);
+        }
+        if vblank {
+            for (adr, value) in self.cheats.ram_writes(self.mbc.ram_bank()) {
+                self.write_word(adr, value);
+            }
+        }
+        let joypad_ints = self.joypad.tick(vblank);
         self.interrupt_flags |= timer_interrupt as u8 | ppu_ints | serial_ints as u8 | joypad_ints;
     }
 }
+
+/// Bits of an io register that always read back as 1, either because they are
+/// unused or write-only. Unmapped registers return None and read as 0xFF.
+fn io_read_mask(adr: u16, cgb: bool) -> Option<u8> {
+    let mask = match adr {
+        0xFF00 => 0xC0,        // P1
+        0xFF01 => 0x00,        // SB
+        0xFF02 if cgb => 0x7C, // SC
+        0xFF02 => 0x7E,
+        0xFF04..=0xFF06 => 0x00,                            // DIV, TIMA, TMA
+        0xFF07 => 0xF8,                                     // TAC
+        0xFF0F => 0xE0,                                     // IF
+        0xFF10 => 0x80,                                     // NR10
+        0xFF11 | 0xFF16 => 0x3F,                            // NR11, NR21
+        0xFF12 | 0xFF17 | 0xFF21 | 0xFF22 => 0x00,          // NR12, NR22, NR42, NR43
+        0xFF13 | 0xFF18 | 0xFF1B | 0xFF1D | 0xFF20 => 0xFF, // write-only
+        0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => 0xBF,          // NR14, NR24, NR34, NR44
+        0xFF1A => 0x7F,                                     // NR30
+        0xFF1C => 0x9F,                                     // NR32
+        0xFF24 | 0xFF25 => 0x00,                            // NR50, NR51
+        0xFF26 => 0x70,                                     // NR52
+        0xFF30..=0xFF3F => 0x00,                            // wave ram
+        0xFF40 => 0x00,                                     // LCDC
+        0xFF41 => 0x80,                                     // STAT
+        0xFF42..=0xFF4B => 0x00,                            // SCY..WX
+        0xFF4D if cgb => 0x7E,                              // KEY1
+        0xFF4F if cgb => 0xFE,                              // VBK
+        0xFF51..=0xFF54 if cgb => 0xFF,                     // HDMA1-4, write-only
+        0xFF55 if cgb => 0x00,                              // HDMA5
+        0xFF56 if cgb => 0x3C,                              // RP
+        0xFF68 | 0xFF6A if cgb => 0x40,                     // BCPS, OCPS
+        0xFF69 | 0xFF6B if cgb => 0x00,                     // BCPD, OCPD
+        0xFF6C if cgb => 0xFE,                              // OPRI
+        0xFF70 if cgb => 0xF8,                              // SVBK
+        0xFF72..=0xFF74 if cgb => 0x00,
+        0xFF75 if cgb => 0x8F,
+        0xFF76 | 0xFF77 if cgb => 0x00, // PCM12, PCM34
+        _ => return None,
+    };
+    Some(mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::Mode;
+    use crate::savestate::{unconnected_receiver, unconnected_sender};
+
+    fn mock_mmu(rom: Vec<u8>) -> MMU {
+        MMU::new(rom, unconnected_sender(), unconnected_receiver())
+    }
+
+    #[test]
+    fn test_boot_rom_unmap() {
+        let mut mmu = mock_mmu(vec![0x11; 0x8000]);
+        assert!(mmu.load_boot_rom(vec![0x22; 0x10]).is_err());
+        mmu.load_boot_rom(vec![0x22; 0x100]).unwrap();
+        assert_eq!(mmu.read_word(0x00FF), 0x22);
+        assert_eq!(mmu.read_word(0x0100), 0x11);
+        mmu.write_word(0xFF50, 0x01);
+        assert_eq!(mmu.read_word(0x00FF), 0x11);
+    }
+
+    #[test]
+    fn test_snapshot_sees_blocked_vram() {
+        let mut mmu = mock_mmu(vec![0; 0x8000]);
+        mmu.write_word(0xFF40, 0x80);
+        mmu.ppu.vram[0] = 0x42;
+        mmu.ppu.oam[0] = 0x24;
+        mmu.ppu.lcd_stat.mode = Mode::TRANSFER;
+        assert_eq!(mmu.read_word(0x8000), 0xFF);
+        assert_eq!(mmu.read_word(0xFE00), 0xFF);
+        let snapshot = mmu.snapshot();
+        assert_eq!(snapshot.read(0x8000), 0x42);
+        assert_eq!(snapshot.read(0xFE00), 0x24);
+    }
+
+    #[test]
+    fn test_snapshot_banks() {
+        let mut rom = vec![0; 0x10000];
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x03;
+        let mut mmu = mock_mmu(rom);
+        mmu.write_word(0x2000, 3);
+        mmu.write_word(0x4000, 2);
+        mmu.write_word(0x6000, 1);
+        let snapshot = mmu.snapshot();
+        assert_eq!(snapshot.bank(0x4000), Some(3));
+        assert_eq!(snapshot.bank(0xA000), Some(2));
+    }
+
+    #[test]
+    fn test_skip_boot_model() {
+        let mut mmu = mock_mmu(vec![0; 0x8000]);
+        mmu.model = Model::CGB;
+        mmu.skip_boot();
+        assert_eq!(mmu.read_word(0xFF04), 0x26);
+        assert_eq!(mmu.read_word(0xFF02), 0x7F);
+        assert_eq!(mmu.read_word(0xFF0F), 0xE1);
+        assert_eq!(mmu.read_word(0xFF44), 144);
+    }
+
+    #[test]
+    fn test_unused_io_bits() {
+        let mut mmu = mock_mmu(vec![0; 0x8000]);
+        mmu.write_word(0xFF03, 0x00);
+        assert_eq!(mmu.read_word(0xFF03), 0xFF);
+        mmu.write_word(0xFF10, 0x00);
+        assert_eq!(mmu.read_word(0xFF10), 0x80);
+        mmu.write_word(0xFF13, 0x00);
+        assert_eq!(mmu.read_word(0xFF13), 0xFF);
+        assert_eq!(mmu.read_word(0xFF0F), 0xE0);
+        mmu.write_word(0xFF4F, 0x00);
+        assert_eq!(mmu.read_word(0xFF4F), 0xFF);
+        mmu.model = Model::CGB;
+        mmu.write_word(0xFF4F, 0x00);
+        assert_eq!(mmu.read_word(0xFF4F), 0xFE);
+    }
+
+    #[test]
+    fn test_oam_dma_blocks_bus() {
+        let mut mmu = mock_mmu(vec![0; 0x8000]);
+        mmu.write_word(0xC000, 0x42);
+        mmu.write_word(0xC001, 0x43);
+        mmu.write_word(0xFF80, 0x99);
+        mmu.write_word(0xFF46, 0xE0);
+        assert_eq!(mmu.read_word(0xFF46), 0xE0);
+        mmu.tick();
+        assert_eq!(mmu.read_word(0xC001), 0x43);
+        mmu.tick();
+        assert_eq!(mmu.read_word(0xFE00), 0xFF);
+        assert_eq!(mmu.read_word(0xC001), 0x42);
+        assert_eq!(mmu.read_word(0xFF80), 0x99);
+        for _ in 0..160 {
+            mmu.tick();
+        }
+        assert_eq!(mmu.read_word(0xFE00), 0x42);
+        assert_eq!(mmu.read_word(0xFE01), 0x43);
+    }
+}
@@ -0,0 +1,92 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+/// Hardware revisions that differ in the state the boot rom leaves behind.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum Model {
+    DMG0,
+    #[default]
+    DMG,
+    MGB,
+    SGB,
+    SGB2,
+    CGB,
+    AGB,
+}
+
+impl Model {
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, Model::CGB | Model::AGB)
+    }
+
+    pub fn is_sgb(&self) -> bool {
+        matches!(self, Model::SGB | Model::SGB2)
+    }
+
+    /// AF, BC, DE and HL at 0x0100, as checked by mooneye boot_regs-*
+    pub fn registers(&self) -> (u16, u16, u16, u16) {
+        match self {
+            Model::DMG0 => (0x0100, 0xFF13, 0x00C1, 0x8403),
+            Model::DMG => (0x01B0, 0x0013, 0x00D8, 0x014D),
+            Model::MGB => (0xFFB0, 0x0013, 0x00D8, 0x014D),
+            Model::SGB => (0x0100, 0x0014, 0x0000, 0xC060),
+            Model::SGB2 => (0xFF00, 0x0014, 0x0000, 0xC060),
+            Model::CGB => (0x1180, 0x0000, 0xFF56, 0x000D),
+            Model::AGB => (0x1100, 0x0100, 0xFF56, 0x000D),
+        }
+    }
+
+    /// Internal 16 bit divider at 0x0100, as checked by mooneye boot_div-*
+    pub fn div(&self) -> u16 {
+        match self {
+            Model::DMG0 => 0x182C,
+            Model::DMG | Model::MGB => 0xABCC,
+            Model::SGB | Model::SGB2 => 0xD85C,
+            Model::CGB | Model::AGB => 0x267C,
+        }
+    }
+
+    /// Scanline and dot within that scanline the ppu is at when 0x0100 is reached
+    pub fn ppu_position(&self) -> (u8, u16) {
+        match self {
+            Model::DMG0 => (145, 0),
+            Model::DMG | Model::MGB => (153, 400),
+            Model::SGB | Model::SGB2 => (0, 0),
+            Model::CGB | Model::AGB => (144, 0),
+        }
+    }
+
+    /// IO registers that differ between models once the boot rom is done
+    pub fn io_registers(&self) -> Vec<(u16, u8)> {
+        let mut regs = vec![
+            (0xFF00, if self.is_sgb() { 0xFF } else { 0xCF }),
+            (0xFF02, if self.is_cgb() { 0x7F } else { 0x7E }),
+            (0xFF26, if self.is_sgb() { 0xF0 } else { 0xF1 }),
+        ];
+        if !self.is_cgb() {
+            // CGB boot roms leave the object palettes uninitialized
+            regs.push((0xFF48, 0xFF));
+            regs.push((0xFF49, 0xFF));
+        }
+        regs
+    }
+}
+
+impl FromStr for Model {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "dmg0" => Ok(Model::DMG0),
+            "dmg" => Ok(Model::DMG),
+            "mgb" => Ok(Model::MGB),
+            "sgb" => Ok(Model::SGB),
+            "sgb2" => Ok(Model::SGB2),
+            "cgb" => Ok(Model::CGB),
+            "agb" => Ok(Model::AGB),
+            _ => Err(anyhow!("unknown model '{}'", s)),
+        }
+    }
+}
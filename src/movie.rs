@@ -0,0 +1,73 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::input::Control;
+use crate::joypad::Key;
+
+/// A key pressed or released at the start of `frame`, counting from 0
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MovieEvent {
+    pub frame: u32,
+    pub key: Key,
+    pub pressed: bool,
+}
+
+/// Input recorded per frame, played back by headless runs
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Movie {
+    events: Vec<MovieEvent>,
+}
+
+impl Movie {
+    pub fn new(mut events: Vec<MovieEvent>) -> Self {
+        events.sort_by_key(|e| e.frame);
+        Movie { events }
+    }
+
+    /// Reads a json array of events
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("unable to open '{}'", path.display()))?;
+        Ok(Movie::new(serde_json::from_str(&text)?))
+    }
+
+    pub fn controls(&self, frame: u32) -> impl Iterator<Item = Control> + '_ {
+        self.events
+            .iter()
+            .filter(move |e| e.frame == frame)
+            .map(|e| {
+                if e.pressed {
+                    Control::KeyDown(e.key)
+                } else {
+                    Control::KeyUp(e.key)
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_movie_controls() {
+        let json = r#"[
+            {"frame": 3, "key": "A", "pressed": false},
+            {"frame": 1, "key": "A", "pressed": true},
+            {"frame": 1, "key": "Left", "pressed": true}
+        ]"#;
+        let movie = Movie::new(serde_json::from_str(json).unwrap());
+        assert_eq!(movie.controls(0).count(), 0);
+        assert_eq!(
+            movie.controls(1).collect::<Vec<_>>(),
+            vec![Control::KeyDown(Key::A), Control::KeyDown(Key::Left)]
+        );
+        assert_eq!(
+            movie.controls(3).collect::<Vec<_>>(),
+            vec![Control::KeyUp(Key::A)]
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use std::time::{Duration, Instant};
+
+use crate::CPU_CLOCK;
+
+// Falling further behind than this resyncs instead of rushing to catch up
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+/// Keeps emulation in step with the wall clock at `speed` times CPU_CLOCK.
+pub struct Pacer {
+    origin: Instant,
+    cycles: u64,
+    speed: f64,
+}
+
+impl Pacer {
+    pub fn new() -> Self {
+        Pacer {
+            origin: Instant::now(),
+            cycles: 0,
+            speed: 1.0,
+        }
+    }
+
+    /// An infinite speed runs as fast as the host allows
+    pub fn set_speed(&mut self, speed: f64) {
+        if speed != self.speed {
+            self.speed = speed;
+            self.restart();
+        }
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Forgets the time spent so far, e.g. after being paused
+    pub fn restart(&mut self) {
+        self.origin = Instant::now();
+        self.cycles = 0;
+    }
+
+    /// Accounts for `cycles` just emulated and returns how long to wait
+    /// before emulating more
+    pub fn advance(&mut self, cycles: u32) -> Duration {
+        self.cycles += cycles as u64;
+        if self.speed.is_infinite() {
+            return Duration::from_secs(0);
+        }
+        let target = Duration::from_secs_f64(self.cycles as f64 / (CPU_CLOCK as f64 * self.speed));
+        let elapsed = self.origin.elapsed();
+        if target > elapsed {
+            target - elapsed
+        } else {
+            if elapsed - target > MAX_LAG {
+                self.restart();
+            }
+            Duration::from_secs(0)
+        }
+    }
+}
+
+impl Default for Pacer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pacer_speed() {
+        let mut pacer = Pacer::new();
+        let delay = pacer.advance(CPU_CLOCK / 10);
+        assert!(delay > Duration::from_millis(90) && delay <= Duration::from_millis(100));
+        pacer.set_speed(2.0);
+        let delay = pacer.advance(CPU_CLOCK / 10);
+        assert!(delay > Duration::from_millis(40) && delay <= Duration::from_millis(50));
+        pacer.set_speed(f64::INFINITY);
+        assert_eq!(pacer.advance(CPU_CLOCK), Duration::from_secs(0));
+    }
+}
@@ -0,0 +1,312 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use serde::Deserialize;
+
+pub type Colour = (u8, u8, u8);
+/// Colours for the four shades, from lightest to darkest
+pub type Shades = [Colour; 4];
+
+// Screen buffer pixels keep the palette register they went through above the
+// shade, so the background and both object palettes can be coloured apart
+pub const LAYER_BG: u8 = 0;
+pub const LAYER_OBJ0: u8 = 1 << 2;
+pub const LAYER_OBJ1: u8 = 2 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Palette {
+    pub bg: Shades,
+    pub obj0: Shades,
+    pub obj1: Shades,
+}
+
+const GREEN_SHADES: Shades = [(224, 248, 208), (136, 192, 112), (52, 104, 86), (8, 24, 32)];
+const GREY_SHADES: Shades = [(255, 255, 255), (170, 170, 170), (85, 85, 85), (0, 0, 0)];
+
+pub const GREEN: Palette = Palette::uniform(GREEN_SHADES);
+pub const GREY: Palette = Palette::uniform(GREY_SHADES);
+/// The greyish screen of the Game Boy Pocket
+pub const POCKET: Palette =
+    Palette::uniform([(196, 207, 161), (139, 149, 109), (77, 83, 60), (31, 31, 31)]);
+/// The blue-green backlight of the Game Boy Light
+pub const LIGHT: Palette =
+    Palette::uniform([(0, 181, 129), (0, 154, 113), (0, 105, 74), (0, 79, 59)]);
+
+impl Palette {
+    pub const fn uniform(shades: Shades) -> Self {
+        Palette {
+            bg: shades,
+            obj0: shades,
+            obj1: shades,
+        }
+    }
+
+    pub fn colour(&self, pixel: u8) -> Colour {
+        let shades = match pixel & !0b11 {
+            LAYER_OBJ0 => &self.obj0,
+            LAYER_OBJ1 => &self.obj1,
+            _ => &self.bg,
+        };
+        shades[(pixel & 0b11) as usize]
+    }
+
+    /// Reads a palette defined as `#rrggbb` colours, as json or toml for any
+    /// other extension. `obj0` and `obj1` fall back to `bg` when left out:
+    ///
+    /// ```toml
+    /// bg = ["#ffffff", "#aaaaaa", "#555555", "#000000"]
+    /// obj0 = ["#ffffff", "#ff8484", "#943a3a", "#000000"]
+    /// ```
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("unable to open '{}'", path.display()))?;
+        let config: PaletteConfig = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&text)?,
+            _ => toml::from_str(&text)?,
+        };
+        let bg = parse_shades(&config.bg)?;
+        let obj0 = match &config.obj0 {
+            Some(shades) => parse_shades(shades)?,
+            None => bg,
+        };
+        let obj1 = match &config.obj1 {
+            Some(shades) => parse_shades(shades)?,
+            None => bg,
+        };
+        Ok(Palette { bg, obj0, obj1 })
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        GREEN
+    }
+}
+
+impl FromStr for Palette {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "green" => Ok(GREEN),
+            "grey" | "gray" => Ok(GREY),
+            "pocket" => Ok(POCKET),
+            "light" => Ok(LIGHT),
+            _ => Err(anyhow!("unknown palette '{}'", s)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PaletteConfig {
+    bg: [String; 4],
+    obj0: Option<[String; 4]>,
+    obj1: Option<[String; 4]>,
+}
+
+fn parse_shades(colours: &[String; 4]) -> Result<Shades> {
+    let mut shades = [(0, 0, 0); 4];
+    for (shade, colour) in shades.iter_mut().zip(colours) {
+        *shade = parse_colour(colour)?;
+    }
+    Ok(shades)
+}
+
+fn parse_colour(colour: &str) -> Result<Colour> {
+    let hex = colour.trim_start_matches('#');
+    if hex.len() != 6 {
+        bail!("colour '{}' is not #rrggbb", colour);
+    }
+    let rgb = u32::from_str_radix(hex, 16)
+        .with_context(|| format!("colour '{}' is not #rrggbb", colour))?;
+    Ok(((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8))
+}
+
+/// 15 bit bgr to rgb
+pub(crate) const fn rgb(colour: u16) -> Colour {
+    const fn channel(colour: u16, shift: u16) -> u8 {
+        let c = ((colour >> shift) & 0x1F) as u8;
+        c << 3 | c >> 2
+    }
+    (channel(colour, 0), channel(colour, 5), channel(colour, 10))
+}
+
+// The tables of the CGB boot rom that colour DMG games
+
+/// Palettes of 4 colours, as 15 bit bgr
+#[rustfmt::skip]
+const CGB_COLOURS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000, 0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000, 0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000, 0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000, 0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000, 0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B, 0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000, 0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000, 0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000, 0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000, 0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000, 0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00, 0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000, 0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000, 0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000, 0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+/// OBJ0, OBJ1 and BG of every combination, as offsets in colours into
+/// `CGB_COLOURS`. A few start in the middle of a palette.
+#[rustfmt::skip]
+const CGB_COMBINATIONS: [[u8; 3]; 51] = [
+    [16, 16, 116], [72, 72, 72], [80, 80, 80], [96, 96, 96], [36, 36, 36],
+    [0, 0, 0], [108, 108, 108], [20, 20, 20], [48, 48, 48], [104, 104, 104],
+    [64, 32, 32], [16, 112, 112], [16, 8, 8], [12, 16, 16], [16, 116, 116],
+    [112, 16, 112], [8, 68, 8], [64, 64, 32], [16, 16, 28], [16, 16, 72],
+    [16, 16, 80], [76, 76, 36], [15, 15, 44], [68, 68, 8], [16, 16, 8],
+    [16, 16, 12], [112, 112, 0], [12, 12, 0], [0, 0, 4], [72, 88, 72],
+    [80, 88, 80], [96, 88, 96], [64, 88, 32], [68, 16, 52], [111, 0, 56],
+    [111, 16, 60], [76, 88, 36], [64, 112, 40], [16, 92, 112], [68, 88, 8],
+    [16, 0, 8], [16, 112, 12], [112, 12, 0], [12, 112, 16], [84, 112, 16],
+    [12, 112, 0], [100, 12, 112], [0, 112, 32], [16, 12, 112], [112, 12, 24],
+    [16, 112, 116],
+];
+
+/// Title checksums. The last 14 are shared by several games, which the
+/// fourth title letter tells apart.
+#[rustfmt::skip]
+const CGB_CHECKSUMS: [u8; 79] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58,
+    0xC9, 0x3E, 0x70, 0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95,
+    0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97, 0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6,
+    0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE, 0x0C, 0x29, 0xE8, 0xB7,
+    0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F, 0x6B,
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D,
+    0xF4,
+];
+const CGB_UNIQUE_CHECKSUMS: usize = 65;
+const CGB_SHARED_CHECKSUMS: usize = CGB_CHECKSUMS.len() - CGB_UNIQUE_CHECKSUMS;
+
+/// Fourth letters for the shared checksums, repeating the checksums every 14
+const CGB_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+/// The combination of every unique checksum, followed by that of every letter
+#[rustfmt::skip]
+const CGB_GAMES: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44, 21, 32, 31, 20,
+    5, 33, 13, 14, 5, 29, 5, 18, 9, 3, 2, 26, 25, 25, 41, 42, 26, 45, 42, 45,
+    36, 38, 26, 42, 30, 41, 34, 34, 5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25,
+    42, 42, 5, 0, 39,
+    36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50, 17, 46, 6, 27, 0, 47,
+    41, 41, 0, 0, 19, 34, 23, 18, 29,
+];
+
+fn cgb_combination(index: usize) -> Palette {
+    let shades = |offset: u8| {
+        let colours = &CGB_COLOURS[offset as usize..offset as usize + 4];
+        [
+            rgb(colours[0]),
+            rgb(colours[1]),
+            rgb(colours[2]),
+            rgb(colours[3]),
+        ]
+    };
+    let [obj0, obj1, bg] = CGB_COMBINATIONS[index];
+    Palette {
+        bg: shades(bg),
+        obj0: shades(obj0),
+        obj1: shades(obj1),
+    }
+}
+
+/// Index into `CGB_GAMES` for a title, 0 for unknown titles
+fn cgb_game(title: &[u8]) -> usize {
+    let checksum = title.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    let index = match CGB_CHECKSUMS.iter().position(|&sum| sum == checksum) {
+        Some(index) => index,
+        None => return 0,
+    };
+    if index < CGB_UNIQUE_CHECKSUMS {
+        return index;
+    }
+    (index - CGB_UNIQUE_CHECKSUMS..CGB_LETTERS.len())
+        .step_by(CGB_SHARED_CHECKSUMS)
+        .find(|&letter| CGB_LETTERS[letter] == title[3])
+        .map_or(0, |letter| CGB_UNIQUE_CHECKSUMS + letter)
+}
+
+/// The palette a CGB colours a DMG game with, from the cartridge header.
+/// `None` for games with CGB support, which don't run in compatibility mode.
+pub fn cgb_compat(rom: &[u8]) -> Option<Palette> {
+    let header = rom.get(0x134..0x150)?;
+    if header[0x143 - 0x134] & 0x80 != 0 {
+        return None;
+    }
+    // only Nintendo's own games are looked up
+    let old_licensee = header[0x14B - 0x134];
+    let new_licensee = &header[0x144 - 0x134..=0x145 - 0x134];
+    if old_licensee != 0x01 && !(old_licensee == 0x33 && new_licensee == b"01") {
+        return Some(cgb_combination(0));
+    }
+    let game = cgb_game(&header[..16]);
+    Some(cgb_combination(CGB_GAMES[game] as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: Colour = (255, 255, 255);
+    const BLACK: Colour = (0, 0, 0);
+    const RED: Shades = [WHITE, (255, 132, 132), (148, 57, 57), BLACK];
+
+    fn nintendo_rom(title: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x150];
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom[0x14B] = 0x01;
+        rom
+    }
+
+    #[test]
+    fn test_cgb_compat_title() {
+        let mut rom = nintendo_rom(b"POKEMON RED");
+        let red = cgb_compat(&rom).unwrap();
+        assert_eq!((red.bg, red.obj1), (RED, RED));
+        assert_eq!(red.obj0[1], (123, 255, 49));
+        rom[0x14B] = 0x33;
+        assert_eq!(cgb_compat(&rom), Some(cgb_combination(0)));
+        rom[0x144..0x146].copy_from_slice(b"01");
+        assert_eq!(cgb_compat(&rom), Some(red));
+        rom[0x143] = 0x80;
+        assert_eq!(cgb_compat(&rom), None);
+
+        let tetris = cgb_compat(&nintendo_rom(b"TETRIS")).unwrap();
+        assert_eq!(tetris.bg, [WHITE, (255, 255, 0), (255, 0, 0), BLACK]);
+        let blue = cgb_compat(&nintendo_rom(b"POKEMON BLUE")).unwrap();
+        assert_eq!((blue.obj0, blue.bg[2]), (RED, (0, 0, 255)));
+    }
+
+    #[test]
+    fn test_cgb_compat_fourth_letter() {
+        // all three sum to 0xB3, the last one is no known game
+        assert_eq!(cgb_game(b"TETRIS ATTACK"), 93);
+        assert_eq!(cgb_game(b"MOGURANYA"), 79);
+        assert_eq!(cgb_game(b"TETQIS ATTACL"), 0);
+        // starts with the last colour of the palette before
+        let mario = cgb_compat(&nintendo_rom(b"SUPER MARIOLAND")).unwrap();
+        assert_eq!(mario.obj0, [BLACK, WHITE, RED[1], RED[2]]);
+        assert_eq!(
+            cgb_compat(&nintendo_rom(b"UNKNOWN")),
+            Some(cgb_combination(0))
+        );
+    }
+
+    #[test]
+    fn test_layer_colours() {
+        let palette = Palette { obj1: RED, ..GREY };
+        assert_eq!(palette.colour(1), GREY_SHADES[1]);
+        assert_eq!(palette.colour(LAYER_OBJ0 | 1), GREY_SHADES[1]);
+        assert_eq!(palette.colour(LAYER_OBJ1 | 1), RED[1]);
+        assert_eq!(parse_colour("#FF8484").unwrap(), RED[1]);
+    }
+}
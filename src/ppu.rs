@@ -1,6 +1,11 @@
-use crate::{Interrupt, ScreenSender, BLANK_SCREEN};
+use crate::palette::{LAYER_BG, LAYER_OBJ0, LAYER_OBJ1};
+use crate::savestate::{self, unconnected_sender};
+use crate::{Interrupt, ScreenBuffer, ScreenSender, BLANK_SCREEN};
+use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
+use std::mem;
 
+#[derive(Serialize, Deserialize)]
 pub struct Control {
     lcd_en: bool,
     win_map: bool,
@@ -56,19 +61,10 @@ impl Control {
     }
 
     fn write_word(&mut self, v: u8) {
-        if v & 0b10000000 != 0 && self.lcd_en == false {
-            println!("lcd enable");
-        }
-        if v & 0b10000000 == 0 && self.lcd_en == true {
-            println!("lcd disable");
-        }
         self.lcd_en = (v & 0b10000000) != 0;
         self.win_map = (v & 0b01000000) != 0;
         self.win_en = (v & 0b00100000) != 0;
         self.tile_sel = (v & 0b00010000) != 0;
-        if v & 0b00010000 == 0 {
-            println!("disabling tilesel 0x{:X}", v);
-        }
         self.bg_map = (v & 0b00001000) != 0;
         self.obj_size = (v & 0b00000100) != 0;
         self.obj_en = (v & 0b00000010) != 0;
@@ -76,7 +72,7 @@ impl Control {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum Mode {
     HBlank = 0,
     VBlank = 1,
@@ -84,6 +80,7 @@ pub enum Mode {
     TRANSFER = 3,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Stat {
     int_lyc: bool,
     int_oam: bool,
@@ -129,7 +126,6 @@ impl Stat {
         if self.coincidence_flag {
             flags |= 0b00000100;
         }
-        println!("flags 0x{:X}", flags);
         flags | self.mode as u8
     }
 }
@@ -138,102 +134,129 @@ const VRAM_SIZE: usize = 0x9FFF - 0x8000 + 1;
 const OAM_SIZE: usize = 0xFE9F - 0xFE00 + 1;
 const SCREEN_WIDTH: usize = 160;
 const SCREEN_HEIGHT: usize = 144;
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+const MAX_SPRITES_PER_LINE: usize = 10;
+// The first tile fetched on each line is thrown away
+const FIRST_FETCH_DOTS: u8 = 6;
+const SPRITE_FETCH_DOTS: u8 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+// Palettes are applied when a pixel leaves the fifo, so only indices are kept
+struct Pixel {
+    color: u8,
+    obp1: bool,
+    bg_priority: bool,
+}
+
+impl Pixel {
+    const TRANSPARENT: Pixel = Pixel {
+        color: 0,
+        obp1: false,
+        bg_priority: false,
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct Sprite {
+    y: u8,
+    x: u8,
+    tile: u8,
+    flags: u8,
+}
+
+impl Sprite {
+    fn from_oam(oam: &[u8]) -> Self {
+        Sprite {
+            y: oam[0],
+            x: oam[1],
+            tile: oam[2],
+            flags: oam[3],
+        }
+    }
 
+    fn bg_priority(&self) -> bool {
+        self.flags & 0b10000000 != 0
+    }
+
+    fn y_flip(&self) -> bool {
+        self.flags & 0b01000000 != 0
+    }
+
+    fn x_flip(&self) -> bool {
+        self.flags & 0b00100000 != 0
+    }
+
+    fn obp1(&self) -> bool {
+        self.flags & 0b00010000 != 0
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Fetcher {
     tile_index: u8,
-    tile_map_adr: usize,
-    high_byte: u8,
+    tile_x: u8,
     low_byte: u8,
-    pixel_row: [u8; 8],
+    high_byte: u8,
     state: FetcherStates,
-    y_offset: u8,
+    dots: u8,
+    window: bool,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 enum FetcherStates {
     TileIndex,
-    HighByte,
-    LowByte,
-    Idle,
+    DataLow,
+    DataHigh,
+    Push,
 }
 
 impl Fetcher {
     pub fn new() -> Self {
         Fetcher {
             tile_index: 0,
-            tile_map_adr: 0,
-            high_byte: 0,
+            tile_x: 0,
             low_byte: 0,
-            pixel_row: [0; 8],
+            high_byte: 0,
             state: FetcherStates::TileIndex,
-            y_offset: 0,
-        }
-    }
-
-    fn merge_bytes(&mut self, bgp: u8) {
-        let h = self.high_byte;
-        let l = self.low_byte;
-        for (x, elem) in self.pixel_row.iter_mut().enumerate() {
-            let v = (((l >> (7 - x as u8)) & 1) << 1) + ((h >> (7 - x as u8)) & 1);
-            let shade = (bgp >> (v * 2)) & 0b11;
-            *elem = shade;
+            dots: 0,
+            window: false,
         }
     }
 
-    fn flush_row(&mut self, fifo: &mut PixelFifo) {
-        for i in self.pixel_row.iter() {
-            fifo.push(*i);
-        }
-        self.tile_map_adr += 1;
+    fn reset(&mut self, window: bool) {
+        self.tile_x = 0;
+        self.state = FetcherStates::TileIndex;
+        self.dots = 0;
+        self.window = window;
     }
 
-    pub fn reset(&mut self, bg_map: bool, x: u8, y: u8) {
-        let base_adr: usize = if bg_map { 0x1C00 } else { 0x1800 };
-        self.tile_map_adr = base_adr + (y as usize / 8 * 32) + x as usize / 8;
-        self.state = FetcherStates::TileIndex;
-        self.y_offset = y % 8 * 2;
+    fn is_ready(&self) -> bool {
+        self.state == FetcherStates::Push
     }
+}
 
-    fn get_tile_adr(&self, tile_sel: bool) -> usize {
-        let tile_offset = self.tile_index as usize * 16;
-        let mut adr = tile_offset;
-        // block 0 start differs depending on 8000 or 8800 adr-mode
-        if !tile_sel && self.tile_index < 128 {
-            adr += 0x1000
-        }
-        adr as usize + self.y_offset as usize
+/// Tile row bytes at `tile_index` in either 0x8000 or 0x8800 addressing
+pub(crate) fn tile_row_adr(tile_index: u8, unsigned_adr: bool, row: u8) -> usize {
+    let mut adr = tile_index as usize * 16;
+    // block 0 start differs depending on 8000 or 8800 adr-mode
+    if !unsigned_adr && tile_index < 128 {
+        adr += 0x1000
     }
+    adr + row as usize * 2
+}
 
-    pub fn tick(&mut self, vram: &[u8; 8192], tile_sel: bool, bgp: u8, pixel_fifo: &mut PixelFifo) {
-        self.state = match self.state {
-            FetcherStates::TileIndex => {
-                self.tile_index = vram[self.tile_map_adr];
-                FetcherStates::HighByte
-            }
-            FetcherStates::HighByte => {
-                let adr = self.get_tile_adr(tile_sel);
-                self.high_byte = vram[adr];
-                FetcherStates::LowByte
-            }
-            FetcherStates::LowByte => {
-                let adr = self.get_tile_adr(tile_sel);
-                self.low_byte = vram[adr + 1];
-                self.merge_bytes(bgp);
-                if !pixel_fifo.is_ready() {
-                    self.flush_row(pixel_fifo);
-                    FetcherStates::TileIndex
-                } else {
-                    FetcherStates::Idle
-                }
-            }
-            FetcherStates::Idle => {
-                self.flush_row(pixel_fifo);
-                FetcherStates::TileIndex
-            }
-        }
+/// Colour indices of a tile row, leftmost pixel first
+pub(crate) fn merge_bytes(low: u8, high: u8) -> [u8; 8] {
+    let mut row = [0; 8];
+    for (x, elem) in row.iter_mut().enumerate() {
+        let bit = 7 - x as u8;
+        *elem = (((high >> bit) & 1) << 1) | ((low >> bit) & 1);
     }
+    row
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct PPU {
     control: Control,
     pub lcd_stat: Stat,
@@ -242,21 +265,38 @@ pub struct PPU {
     pub ly: u8,
     pub lyc: u8,
     pub wy: u8,
+    pub wx: u8,
     pub bgp: u8,
     pub obp0: u8,
     pub obp1: u8,
-    pub vram: [u8; VRAM_SIZE],
+    #[serde(with = "savestate::bytes")]
+    pub vram: Box<[u8; VRAM_SIZE]>,
+    #[serde(with = "savestate::bytes")]
     pub oam: [u8; OAM_SIZE],
-    cycles_elapsed: u16,
+    #[serde(with = "savestate::screen")]
+    pub screen: Box<ScreenBuffer>,
+    dot: u16,
+    #[serde(skip, default = "unconnected_sender")]
     screen_sender: ScreenSender,
-    pixel_fifo: PixelFifo,
+    bg_fifo: VecDeque<Pixel>,
+    obj_fifo: VecDeque<Pixel>,
     fetcher: Fetcher,
-    is_state_enter: bool,
+    sprites: Vec<Sprite>,
+    sprite_fetch: Option<(Sprite, u8)>,
+    lx: u8,
+    discard: u8,
+    startup_dots: u8,
+    window_y_triggered: bool,
+    window_line: u8,
+    stat_line: bool,
+    stat_irq_pending: bool,
+    first_line: bool,
+    skip_frame: bool,
 }
 
 impl PPU {
     pub fn new(screen_sender: ScreenSender) -> Self {
-        let ppu = PPU {
+        PPU {
             control: Control::new(),
             lcd_stat: Stat::new(),
             scy: 0,
@@ -264,107 +304,386 @@ impl PPU {
             ly: 0,
             lyc: 0,
             wy: 0,
+            wx: 0,
             bgp: 0,
             obp0: 0,
             obp1: 0,
-            vram: [0; VRAM_SIZE],
+            vram: Box::new([0; VRAM_SIZE]),
             oam: [0; OAM_SIZE],
-            cycles_elapsed: 0,
+            screen: Box::new(BLANK_SCREEN),
+            dot: 0,
             screen_sender,
-            pixel_fifo: PixelFifo::new(),
+            bg_fifo: VecDeque::with_capacity(16),
+            obj_fifo: VecDeque::with_capacity(8),
             fetcher: Fetcher::new(),
-            is_state_enter: false,
+            sprites: Vec::with_capacity(MAX_SPRITES_PER_LINE),
+            sprite_fetch: None,
+            lx: 0,
+            discard: 0,
+            startup_dots: 0,
+            window_y_triggered: false,
+            window_line: 0,
+            stat_line: false,
+            stat_irq_pending: false,
+            first_line: false,
+            skip_frame: false,
+        }
+    }
+
+    /// Takes over the channel frames are sent to from `old`
+    pub(crate) fn keep_connections(&mut self, old: &mut PPU) {
+        mem::swap(&mut self.screen_sender, &mut old.screen_sender);
+    }
+
+    /// Moves an enabled lcd to the start of `dot` on scanline `ly`.
+    /// Only positions outside of pixel transfer are supported.
+    pub fn set_position(&mut self, ly: u8, dot: u16) {
+        self.ly = ly;
+        self.dot = dot;
+        self.first_line = false;
+        self.skip_frame = false;
+        self.lcd_stat.mode = if ly >= SCREEN_HEIGHT as u8 {
+            Mode::VBlank
+        } else if dot < OAM_SCAN_DOTS {
+            Mode::OAM
+        } else {
+            self.lx = SCREEN_WIDTH as u8;
+            Mode::HBlank
         };
-        ppu
     }
 
-    fn set_next_state(&mut self, state: Mode) {
-        self.is_state_enter = true;
-        self.lcd_stat.mode = state;
+    /// Never waits for the frontend, a frame it hasn't taken yet means
+    /// this one is dropped
+    fn send_frame(&self, frame: ScreenBuffer) {
+        let _ = self.screen_sender.try_send(frame);
     }
 
-    fn is_state_enter(&mut self) -> bool {
-        let res = self.is_state_enter;
-        self.is_state_enter = false;
-        res
+    /// The lcd stops with LY=0 and STAT in mode 0, leaving a blank screen
+    fn disable_lcd(&mut self) {
+        self.ly = 0;
+        self.dot = 0;
+        self.lcd_stat.mode = Mode::HBlank;
+        self.stat_line = false;
+        self.send_frame(BLANK_SCREEN);
     }
 
-    pub fn tick(&mut self) -> u8 {
-        if !self.control.lcd_en {
-            return Interrupt::NoInterrupt as u8;
+    /// Line 0 after turning the lcd on stays in mode 0 until pixel transfer,
+    /// and the first frame is never shown
+    fn enable_lcd(&mut self) {
+        self.ly = 0;
+        self.dot = 0;
+        self.window_y_triggered = false;
+        self.window_line = 0;
+        self.lcd_stat.mode = Mode::HBlank;
+        self.first_line = true;
+        self.skip_frame = true;
+        self.lcd_stat.coincidence_flag = self.ly == self.lyc;
+        self.stat_line = self.stat_line();
+    }
+
+    /// LY as seen by the cpu, line 153 reports 0 after its first cycle
+    fn read_ly(&self) -> u8 {
+        if self.ly == 153 && self.dot >= 4 {
+            0
+        } else {
+            self.ly
+        }
+    }
+
+    /// All enabled STAT sources are OR:ed into one line, only its rising edge interrupts
+    fn stat_line(&self) -> bool {
+        let stat = &self.lcd_stat;
+        let mode_source = match stat.mode {
+            Mode::HBlank => stat.int_hblank,
+            // the OAM source also triggers when entering line 144
+            Mode::VBlank => stat.int_vblank || (stat.int_oam && self.ly == 144 && self.dot < 4),
+            Mode::OAM => stat.int_oam,
+            Mode::TRANSFER => false,
+        };
+        mode_source || (stat.int_lyc && stat.coincidence_flag)
+    }
+
+    fn update_stat_line(&mut self) -> bool {
+        let line = self.stat_line();
+        let rising = line && !self.stat_line;
+        self.stat_line = line;
+        rising
+    }
+
+    /// On DMG writing STAT briefly enables every source, raising an interrupt
+    /// during HBlank, VBlank or LY=LYC.
+    pub fn stat_write_glitch(&mut self) {
+        if !self.control.lcd_en || self.stat_line {
+            return;
+        }
+        let glitch_line = match self.lcd_stat.mode {
+            Mode::HBlank | Mode::VBlank => true,
+            _ => self.lcd_stat.coincidence_flag,
+        };
+        if glitch_line {
+            self.stat_irq_pending = true;
+        }
+    }
+
+    fn scan_oam(&mut self) {
+        let height = if self.control.obj_size { 16 } else { 8 };
+        let line = self.ly as u16 + 16;
+        self.sprites = self
+            .oam
+            .chunks(4)
+            .map(Sprite::from_oam)
+            .filter(|s| line >= s.y as u16 && line < s.y as u16 + height)
+            .take(MAX_SPRITES_PER_LINE)
+            .collect();
+    }
+
+    fn start_transfer(&mut self) {
+        self.bg_fifo.clear();
+        self.obj_fifo.clear();
+        self.fetcher.reset(false);
+        self.sprite_fetch = None;
+        self.lx = 0;
+        self.discard = self.scx % 8;
+        self.startup_dots = FIRST_FETCH_DOTS;
+    }
+
+    fn fetcher_row(&self) -> u8 {
+        if self.fetcher.window {
+            self.window_line % 8
+        } else {
+            self.ly.wrapping_add(self.scy) % 8
+        }
+    }
+
+    fn fetcher_dot(&mut self) {
+        let row = self.fetcher_row();
+        let fetcher = &mut self.fetcher;
+        fetcher.dots += 1;
+        match fetcher.state {
+            FetcherStates::TileIndex if fetcher.dots == 2 => {
+                let (map, x, y) = if fetcher.window {
+                    (self.control.win_map, fetcher.tile_x, self.window_line)
+                } else {
+                    // SCX and SCY are sampled by each fetch, allowing mid-line scrolling
+                    let x = (self.scx / 8).wrapping_add(fetcher.tile_x) & 31;
+                    (self.control.bg_map, x, self.ly.wrapping_add(self.scy))
+                };
+                let base_adr: usize = if map { 0x1C00 } else { 0x1800 };
+                fetcher.tile_index = self.vram[base_adr + (y as usize / 8 * 32) + x as usize];
+                fetcher.state = FetcherStates::DataLow;
+                fetcher.dots = 0;
+            }
+            FetcherStates::DataLow if fetcher.dots == 2 => {
+                let adr = tile_row_adr(fetcher.tile_index, self.control.tile_sel, row);
+                fetcher.low_byte = self.vram[adr];
+                fetcher.state = FetcherStates::DataHigh;
+                fetcher.dots = 0;
+            }
+            FetcherStates::DataHigh if fetcher.dots == 2 => {
+                let adr = tile_row_adr(fetcher.tile_index, self.control.tile_sel, row);
+                fetcher.high_byte = self.vram[adr + 1];
+                fetcher.state = FetcherStates::Push;
+                fetcher.dots = 0;
+            }
+            FetcherStates::Push => {
+                fetcher.dots = 0;
+                if self.bg_fifo.is_empty() {
+                    for &color in merge_bytes(fetcher.low_byte, fetcher.high_byte).iter() {
+                        self.bg_fifo.push_back(Pixel {
+                            color,
+                            obp1: false,
+                            bg_priority: false,
+                        });
+                    }
+                    fetcher.tile_x = fetcher.tile_x.wrapping_add(1);
+                    fetcher.state = FetcherStates::TileIndex;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn fetch_sprite(&mut self, sprite: Sprite) {
+        let height = if self.control.obj_size { 16 } else { 8 };
+        // OBJ size may have changed since the OAM scan, leaving 8x16 rows on 8x8 sprites
+        let mut row = (self.ly + 16 - sprite.y) & (height - 1);
+        if sprite.y_flip() {
+            row = height - 1 - row;
         }
+        // the lowest bit is ignored for 8x16 sprites
+        let tile = if height == 16 {
+            sprite.tile & 0xFE
+        } else {
+            sprite.tile
+        };
+        let adr = tile_row_adr(tile, true, row);
+        let mut colors = merge_bytes(self.vram[adr], self.vram[adr + 1]);
+        if sprite.x_flip() {
+            colors.reverse();
+        }
+        while self.obj_fifo.len() < 8 {
+            self.obj_fifo.push_back(Pixel::TRANSPARENT);
+        }
+        // sprites hanging off the left edge lose their hidden pixels
+        let skip = 8usize.saturating_sub(sprite.x as usize);
+        for (i, &color) in colors.iter().enumerate().skip(skip) {
+            let slot = &mut self.obj_fifo[i - skip];
+            // earlier fetched sprites keep priority over later ones
+            if slot.color == 0 && color != 0 {
+                *slot = Pixel {
+                    color,
+                    obp1: sprite.obp1(),
+                    bg_priority: sprite.bg_priority(),
+                };
+            }
+        }
+    }
+
+    fn next_sprite(&mut self) -> Option<Sprite> {
+        if !self.control.obj_en {
+            return None;
+        }
+        let lx = self.lx as u16 + 8;
+        let index = self.sprites.iter().position(|s| s.x as u16 <= lx)?;
+        Some(self.sprites.remove(index))
+    }
+
+    fn mix_pixel(&self, bg: Pixel, obj: Option<Pixel>) -> u8 {
+        let bg_color = if self.control.bg_en { bg.color } else { 0 };
+        let obj = obj.filter(|obj| obj.color != 0 && self.control.obj_en);
+        match obj {
+            Some(obj) if !(obj.bg_priority && bg_color != 0) => {
+                let (palette, layer) = if obj.obp1 {
+                    (self.obp1, LAYER_OBJ1)
+                } else {
+                    (self.obp0, LAYER_OBJ0)
+                };
+                (palette >> (obj.color * 2)) & 0b11 | layer
+            }
+            _ => (self.bgp >> (bg_color * 2)) & 0b11 | LAYER_BG,
+        }
+    }
+
+    fn transfer_dot(&mut self) {
+        if self.startup_dots > 0 {
+            self.startup_dots -= 1;
+            return;
+        }
+        if let Some((sprite, dots)) = self.sprite_fetch {
+            // the background fetch in progress has to complete before a sprite is fetched
+            if !self.fetcher.is_ready() {
+                self.fetcher_dot();
+            } else if dots + 1 == SPRITE_FETCH_DOTS {
+                self.sprite_fetch = None;
+                self.fetch_sprite(sprite);
+            } else {
+                self.sprite_fetch = Some((sprite, dots + 1));
+            }
+            return;
+        }
+        if self.discard == 0 {
+            if let Some(sprite) = self.next_sprite() {
+                self.sprite_fetch = Some((sprite, 0));
+                return;
+            }
+        }
+        if !self.fetcher.window
+            && self.control.win_en
+            && self.window_y_triggered
+            && self.lx as u16 + 7 >= self.wx as u16
+        {
+            self.bg_fifo.clear();
+            self.fetcher.reset(true);
+            if self.wx < 7 {
+                self.discard = 7 - self.wx;
+            }
+        }
+        self.fetcher_dot();
+        if let Some(bg) = self.bg_fifo.pop_front() {
+            let obj = self.obj_fifo.pop_front();
+            if self.discard > 0 {
+                self.discard -= 1;
+            } else {
+                self.screen[self.ly as usize][self.lx as usize] = self.mix_pixel(bg, obj);
+                self.lx += 1;
+            }
+        }
+    }
+
+    fn dot(&mut self) -> u8 {
         let mut interrupt = 0;
-        self.cycles_elapsed += 4;
-        let enter = self.is_state_enter();
         match self.lcd_stat.mode {
             Mode::OAM => {
-                if enter && self.lcd_stat.int_oam {
-                    interrupt = Interrupt::LCDStat as u8;
-                } else if self.cycles_elapsed==80 {
-                    self.set_next_state(Mode::TRANSFER);
+                if self.dot == 0 && self.ly == self.wy {
+                    self.window_y_triggered = true;
+                }
+                if self.dot == OAM_SCAN_DOTS - 1 {
+                    self.scan_oam();
+                    self.start_transfer();
+                    self.lcd_stat.mode = Mode::TRANSFER;
                 }
             }
             Mode::TRANSFER => {
-                if enter {
-                    self.fetcher.reset(self.control.bg_map, self.scx, self.ly);
-                    self.pixel_fifo.reset(self.scx);
-                }
-                self.fetcher.tick(
-                    &self.vram,
-                    self.control.tile_sel,
-                    self.bgp,
-                    &mut self.pixel_fifo,
-                );
-                self.fetcher.tick(
-                    &self.vram,
-                    self.control.tile_sel,
-                    self.bgp,
-                    &mut self.pixel_fifo,
-                );
-                self.pixel_fifo.tick(self.ly);
-                self.pixel_fifo.tick(self.ly);
-                self.pixel_fifo.tick(self.ly);
-                self.pixel_fifo.tick(self.ly);
-
-                if self.pixel_fifo.row_is_done() {
-                    self.pixel_fifo.empty_queue();
-                    self.ly += 1;
-
-                    self.set_next_state(Mode::HBlank);
+                self.transfer_dot();
+                if self.lx as usize == SCREEN_WIDTH {
+                    if self.fetcher.window {
+                        self.window_line += 1;
+                    }
+                    self.lcd_stat.mode = Mode::HBlank;
                 }
             }
-            Mode::HBlank => {
-                if enter && self.lcd_stat.int_hblank {
-                    interrupt = Interrupt::LCDStat as u8;
-                } else if self.cycles_elapsed == 456 {
-                    self.cycles_elapsed = 0;
-                    self.set_next_state(match self.ly {
-                        144 => Mode::VBlank,
-                        _ => Mode::OAM,
-                    });
-                }
+            // OAM is not scanned on the first line after the lcd is turned on
+            Mode::HBlank if self.first_line && self.dot == OAM_SCAN_DOTS - 1 => {
+                self.first_line = false;
+                self.scan_oam();
+                self.start_transfer();
+                self.lcd_stat.mode = Mode::TRANSFER;
             }
-            Mode::VBlank => {
-                if enter {
-                    self.screen_sender.send(self.pixel_fifo.screen).unwrap();
-                    interrupt |= Interrupt::VBLANK as u8;
-                    if self.lcd_stat.int_vblank || self.lcd_stat.int_oam {
-                        interrupt |= Interrupt::LCDStat as u8;
-                    }
-                } else if self.cycles_elapsed % 456 == 0 {
-                    self.ly += 1;
-                    if self.ly == 154 {
-                        self.ly = 0;
-                        self.cycles_elapsed = 0;
-                        self.set_next_state(Mode::OAM);
+            Mode::HBlank | Mode::VBlank => {}
+        }
+        self.dot += 1;
+        if self.dot == DOTS_PER_LINE {
+            self.dot = 0;
+            self.ly += 1;
+            match self.ly {
+                144 => {
+                    self.lcd_stat.mode = Mode::VBlank;
+                    if self.skip_frame {
+                        self.skip_frame = false;
+                        self.send_frame(BLANK_SCREEN);
+                    } else {
+                        self.send_frame(*self.screen);
                     }
+                    interrupt |= Interrupt::VBLANK as u8;
                 }
+                154 => {
+                    self.ly = 0;
+                    self.window_y_triggered = false;
+                    self.window_line = 0;
+                    self.lcd_stat.mode = Mode::OAM;
+                }
+                1..=143 => self.lcd_stat.mode = Mode::OAM,
+                _ => {}
             }
         }
+        self.lcd_stat.coincidence_flag = self.read_ly() == self.lyc;
+        if self.update_stat_line() {
+            interrupt |= Interrupt::LCDStat as u8;
+        }
+        interrupt
+    }
 
-        self.lcd_stat.coincidence_flag = self.ly == self.lyc;
-        if self.lcd_stat.int_lyc && self.lcd_stat.coincidence_flag {
+    pub fn tick(&mut self) -> u8 {
+        if !self.control.lcd_en {
+            return Interrupt::NoInterrupt as u8;
+        }
+        let mut interrupt = 0;
+        for _ in 0..4 {
+            interrupt |= self.dot();
+        }
+        if self.stat_irq_pending {
             interrupt |= Interrupt::LCDStat as u8;
+            self.stat_irq_pending = false;
         }
         interrupt
     }
@@ -391,12 +710,13 @@ impl PPU {
             0xFF41 => self.lcd_stat.read_word(),
             0xFF42 => self.scy,
             0xFF43 => self.scx,
-            0xFF44 => self.ly,
+            0xFF44 => self.read_ly(),
             0xFF45 => self.lyc,
             0xFF47 => self.bgp,
             0xFF48 => self.obp0,
             0xFF49 => self.obp1,
             0xFF4A => self.wy,
+            0xFF4B => self.wx,
             _ => panic!("No such ppu adr 0x{:X}", adr),
         }
     }
@@ -421,79 +741,230 @@ impl PPU {
             0xFF40 => {
                 let prev_lcd_en = self.control.lcd_en;
                 self.control.write_word(v);
-                if prev_lcd_en && !self.control.lcd_en {
-                    self.screen_sender.send(BLANK_SCREEN).unwrap();
+                match (prev_lcd_en, self.control.lcd_en) {
+                    (true, false) => self.disable_lcd(),
+                    (false, true) => self.enable_lcd(),
+                    _ => {}
+                }
+            }
+            0xFF41 => {
+                self.lcd_stat.write_word(v);
+                // enabling a source whose condition already holds is a rising edge too
+                if self.control.lcd_en && self.update_stat_line() {
+                    self.stat_irq_pending = true;
                 }
-                self.ly = 0;
-                self.pixel_fifo.reset(0);
-                self.set_next_state(Mode::OAM);
-                self.cycles_elapsed = 0;
             }
-            0xFF41 => self.lcd_stat.write_word(v),
             0xFF42 => self.scy = v,
             0xFF43 => self.scx = v,
             0xFF44 => {} // read only
-            0xFF45 => self.lyc = v,
+            0xFF45 => {
+                self.lyc = v;
+                if self.control.lcd_en {
+                    self.lcd_stat.coincidence_flag = self.read_ly() == self.lyc;
+                    if self.update_stat_line() {
+                        self.stat_irq_pending = true;
+                    }
+                }
+            }
             0xFF47 => self.bgp = v,
             0xFF48 => self.obp0 = v,
             0xFF49 => self.obp1 = v,
             0xFF4A => self.wy = v,
+            0xFF4B => self.wx = v,
             _ => {}
         }
     }
 }
 
-pub struct PixelFifo {
-    queue: VecDeque<u8>,
-    pub screen: [[u8; SCREEN_WIDTH]; SCREEN_HEIGHT],
-    x: usize,
-    scx: u8,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn mock_ppu() -> (PPU, mpsc::Receiver<crate::ScreenBuffer>) {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let mut ppu = PPU::new(tx);
+        ppu.write_word(0xFF40, 0x91);
+        (ppu, rx)
+    }
 
-impl PixelFifo {
-    fn new() -> Self {
-        PixelFifo {
-            queue: VecDeque::with_capacity(16),
-            screen: [[0; SCREEN_WIDTH]; SCREEN_HEIGHT],
-            x: 0,
-            scx: 0,
+    #[test]
+    fn test_lyc_interrupt_on_rising_edge_only() {
+        let (mut ppu, _rx) = mock_ppu();
+        ppu.write_word(0xFF45, 2);
+        ppu.write_word(0xFF41, 0b01000000);
+        let mut lcd_ints = 0;
+        for _ in 0..456 {
+            if ppu.tick() & Interrupt::LCDStat as u8 != 0 {
+                lcd_ints += 1;
+            }
         }
+        assert_eq!(lcd_ints, 1);
     }
 
-    pub fn is_ready(&self) -> bool {
-        self.queue.len() > 8
+    #[test]
+    fn test_enabling_lyc_source_while_ly_equals_lyc() {
+        let (mut ppu, _rx) = mock_ppu();
+        ppu.write_word(0xFF45, 0);
+        ppu.write_word(0xFF41, 0b01000000);
+        assert_ne!(ppu.tick() & Interrupt::LCDStat as u8, 0);
+        // already high, so writing it again doesn't interrupt
+        ppu.write_word(0xFF41, 0b01000000);
+        assert_eq!(ppu.tick() & Interrupt::LCDStat as u8, 0);
     }
-    pub fn push(&mut self, v: u8) {
-        self.queue.push_back(v);
+
+    #[test]
+    fn test_stat_blocking() {
+        let (mut ppu, _rx) = mock_ppu();
+        // enabled while the line is low, line 0 starting in mode 0 would raise it
+        while ppu.lcd_stat.mode != Mode::TRANSFER {
+            ppu.dot();
+        }
+        // HBlank of line 0 followed by OAM of line 1 keeps the line high
+        ppu.write_word(0xFF41, 0b00101000);
+        let mut lcd_ints = 0;
+        for _ in 0..(456 * 2 / 4) {
+            if ppu.tick() & Interrupt::LCDStat as u8 != 0 {
+                lcd_ints += 1;
+            }
+        }
+        assert_eq!(lcd_ints, 2);
     }
 
-    pub fn pop(&mut self) -> Option<u8> {
-        self.queue.pop_front()
+    #[test]
+    fn test_frames_nobody_takes_dont_block() {
+        let (mut ppu, _rx) = mock_ppu();
+        let (done, vblanks) = mpsc::channel();
+        // a blocking send would hang the ppu after the first frame
+        thread::spawn(move || {
+            let vblanks = (0..(5 * crate::FRAME_CYCLES / 4))
+                .filter(|_| ppu.tick() & Interrupt::VBLANK as u8 != 0)
+                .count();
+            done.send(vblanks).unwrap();
+        });
+        assert_eq!(vblanks.recv_timeout(Duration::from_secs(10)), Ok(5));
     }
 
-    fn reset(&mut self, scx: u8) {
-        self.queue.clear();
-        self.scx = scx % 8;
-        self.x = 0;
+    #[test]
+    fn test_line_153_reads_zero() {
+        let (mut ppu, _rx) = mock_ppu();
+        while ppu.read_word(0xFF44) != 153 {
+            ppu.tick();
+        }
+        ppu.tick();
+        assert_eq!(ppu.read_word(0xFF44), 0);
+        assert_eq!(ppu.lcd_stat.mode, Mode::VBlank);
     }
 
-    fn row_is_done(&self) -> bool {
-        self.x == 160
+    fn mode3_length(ppu: &mut PPU) -> u16 {
+        while ppu.lcd_stat.mode != Mode::TRANSFER {
+            ppu.dot();
+        }
+        let mut dots = 0;
+        while ppu.lcd_stat.mode == Mode::TRANSFER {
+            ppu.dot();
+            dots += 1;
+        }
+        dots
     }
 
-    fn empty_queue(&mut self) {
-        self.queue.clear();
+    #[test]
+    fn test_mode3_length() {
+        let (mut ppu, _rx) = mock_ppu();
+        assert_eq!(mode3_length(&mut ppu), 172);
+        ppu.scx = 3;
+        assert_eq!(mode3_length(&mut ppu), 175);
     }
 
-    pub fn tick(&mut self, y: u8) {
-        if self.is_ready() {
-            let v = self.pop().unwrap();
-            if self.scx > 0 {
-                self.scx -= 1;
-            } else {
-                self.screen[y as usize][self.x] = v;
-                self.x += 1;
-            }
+    #[test]
+    fn test_mode3_sprite_penalty() {
+        let (mut ppu, _rx) = mock_ppu();
+        ppu.write_word(0xFF40, 0x93);
+        ppu.oam[0] = 16;
+        ppu.oam[1] = 8;
+        let length = mode3_length(&mut ppu);
+        assert!((172 + 6..=172 + 11).contains(&length), "{}", length);
+        ppu.write_word(0xFF40, 0x91);
+        assert_eq!(mode3_length(&mut ppu), 172);
+    }
+
+    #[test]
+    fn test_sprite_pixels() {
+        let (mut ppu, _rx) = mock_ppu();
+        ppu.write_word(0xFF40, 0x93);
+        ppu.bgp = 0b11100100;
+        ppu.obp0 = 0b11100100;
+        // tile 1 row 0 is colour 3 on the left half
+        ppu.vram[16] = 0xF0;
+        ppu.vram[17] = 0xF0;
+        ppu.oam[0..4].copy_from_slice(&[16, 10, 1, 0]);
+        while ppu.lcd_stat.mode != Mode::TRANSFER {
+            ppu.dot();
+        }
+        while ppu.lcd_stat.mode != Mode::HBlank {
+            ppu.dot();
+        }
+        let obj = 3 | LAYER_OBJ0;
+        assert_eq!(&ppu.screen[0][0..8], &[0, 0, obj, obj, obj, obj, 0, 0]);
+    }
+
+    #[test]
+    fn test_obj_size_change_after_oam_scan() {
+        let (mut ppu, _rx) = mock_ppu();
+        ppu.write_word(0xFF40, 0x97);
+        ppu.obp0 = 0b11100100;
+        // line 0 is row 8 of the 8x16 sprite, which becomes row 0 of tile 1
+        // once it is 8x8, drawn flipped from row 7
+        ppu.vram[16 + 14] = 0xF0;
+        ppu.vram[16 + 15] = 0xF0;
+        ppu.oam[0..4].copy_from_slice(&[8, 8, 1, 0x40]);
+        while ppu.lcd_stat.mode != Mode::TRANSFER {
+            ppu.dot();
+        }
+        ppu.write_word(0xFF40, 0x93);
+        while ppu.lcd_stat.mode != Mode::HBlank {
+            ppu.dot();
+        }
+        let obj = 3 | LAYER_OBJ0;
+        assert_eq!(&ppu.screen[0][0..6], &[obj, obj, obj, obj, 0, 0]);
+    }
+
+    #[test]
+    fn test_scy_and_mid_line_bgp() {
+        let (mut ppu, _rx) = mock_ppu();
+        ppu.bgp = 0b11100100;
+        ppu.scy = 8;
+        // tile 1 is solid colour 1 and placed on the second map row
+        ppu.vram[16] = 0xFF;
+        ppu.vram[0x1800 + 32] = 1;
+        while ppu.lcd_stat.mode != Mode::TRANSFER {
+            ppu.dot();
+        }
+        while ppu.lx < 80 {
+            ppu.dot();
+        }
+        ppu.bgp = 0b11111111;
+        while ppu.lcd_stat.mode != Mode::HBlank {
+            ppu.dot();
+        }
+        assert_eq!(ppu.screen[0][0], 1);
+        assert_eq!(ppu.screen[0][159], 3);
+    }
+
+    #[test]
+    fn test_lcdc_writes() {
+        let (mut ppu, rx) = mock_ppu();
+        assert_eq!(ppu.read_word(0xFF41) & 0b11, Mode::HBlank as u8);
+        while ppu.ly != 10 {
+            ppu.tick();
         }
+        ppu.write_word(0xFF40, 0x93);
+        assert_eq!(ppu.ly, 10);
+        ppu.write_word(0xFF40, 0x13);
+        assert_eq!(ppu.read_word(0xFF44), 0);
+        assert_eq!(ppu.read_word(0xFF41) & 0b11, Mode::HBlank as u8);
+        assert_eq!(rx.recv().unwrap()[0][0], 0);
     }
 }
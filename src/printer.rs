@@ -0,0 +1,351 @@
+use std::fs;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::serial::SerialPeer;
+
+const PRINT_WIDTH: usize = 160;
+// Bytes in a full tile row, 20 tiles of 16 bytes
+const TILE_ROW_BYTES: usize = 20 * 16;
+// A data packet holds two tile rows, nine of them fill the printer ram for one screen
+const PACKET_BYTES: usize = 2 * TILE_ROW_BYTES;
+const BUFFER_SIZE: usize = 9 * PACKET_BYTES;
+// Status packets answered as busy after a print command
+const PRINT_POLLS: u8 = 4;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0b0000_0001;
+const STATUS_PRINTING: u8 = 0b0000_0010;
+const STATUS_IMAGE_FULL: u8 = 0b0000_0100;
+const STATUS_UNPROCESSED: u8 = 0b0000_1000;
+const STATUS_PACKET_ERROR: u8 = 0b0001_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+/// Game Boy Printer attached as the peer of the serial port.
+/// Every print that feeds the paper out is written as a png into `output_dir`.
+pub struct Printer {
+    output_dir: PathBuf,
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    buffer: Vec<u8>,
+    status: u8,
+    busy_polls: u8,
+    paper: Vec<[u8; PRINT_WIDTH]>,
+    prints: u32,
+}
+
+impl Printer {
+    pub fn new(output_dir: impl Into<PathBuf>) -> Self {
+        Printer {
+            output_dir: output_dir.into(),
+            state: State::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+            status: 0,
+            busy_polls: 0,
+            paper: Vec::new(),
+            prints: 0,
+        }
+    }
+
+    fn handle_packet(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+        match self.command {
+            // init
+            0x01 => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy_polls = 0;
+            }
+            // print
+            0x02 if self.data.len() >= 4 => {
+                let (margins, palette) = (self.data[1], self.data[2]);
+                self.print(margins, palette);
+                self.busy_polls = PRINT_POLLS;
+                self.status |= STATUS_PRINTING;
+            }
+            // data, an empty packet only marks the end of the image
+            0x04 => {
+                if self.compressed {
+                    let data = decompress(&self.data);
+                    self.append(&data);
+                } else {
+                    let data = std::mem::take(&mut self.data);
+                    self.append(&data);
+                }
+            }
+            // status
+            0x0F => {
+                if self.busy_polls > 0 {
+                    self.busy_polls -= 1;
+                    if self.busy_polls == 0 {
+                        self.status &= !STATUS_PRINTING;
+                    }
+                }
+            }
+            _ => self.status |= STATUS_PACKET_ERROR,
+        }
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        let free = BUFFER_SIZE - self.buffer.len();
+        self.buffer.extend_from_slice(&data[..data.len().min(free)]);
+        if !self.buffer.is_empty() {
+            self.status |= STATUS_UNPROCESSED;
+        }
+        if self.buffer.len() == BUFFER_SIZE {
+            self.status |= STATUS_IMAGE_FULL;
+        }
+    }
+
+    /// Prints the buffered image onto the paper. Games split long images into
+    /// several prints without a margin in between, the paper is only cut and
+    /// saved once a print ends with a feed.
+    fn print(&mut self, margins: u8, palette: u8) {
+        // a palette of 0 is treated as the default one
+        let palette = if palette == 0 { 0xE4 } else { palette };
+        self.paper.extend(render(&self.buffer, palette));
+        self.buffer.clear();
+        self.status &= !(STATUS_UNPROCESSED | STATUS_IMAGE_FULL);
+        if margins & 0x0F != 0 && !self.paper.is_empty() {
+            self.prints += 1;
+            let path = self
+                .output_dir
+                .join(format!("print_{:03}.png", self.prints));
+            if let Err(e) = save_print(&self.paper, &path) {
+                eprintln!("Unable to save print '{}': {}", path.display(), e);
+            }
+            self.paper.clear();
+        }
+    }
+}
+
+impl SerialPeer for Printer {
+    /// The printer answers every byte right away
+    fn transfer(&mut self, out: u8) -> Option<u8> {
+        let mut reply = 0x00;
+        self.state = match self.state {
+            State::Magic1 if out == 0x88 => State::Magic2,
+            State::Magic1 => State::Magic1,
+            State::Magic2 if out == 0x33 => State::Command,
+            State::Magic2 => State::Magic1,
+            State::Command => {
+                self.command = out;
+                self.checksum = out as u16;
+                State::Compression
+            }
+            State::Compression => {
+                self.compressed = out & 1 != 0;
+                self.checksum = self.checksum.wrapping_add(out as u16);
+                State::LengthLow
+            }
+            State::LengthLow => {
+                self.length = out as u16;
+                self.checksum = self.checksum.wrapping_add(out as u16);
+                State::LengthHigh
+            }
+            State::LengthHigh => {
+                self.length |= (out as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(out as u16);
+                self.data.clear();
+                if self.length == 0 {
+                    State::ChecksumLow
+                } else {
+                    State::Data
+                }
+            }
+            State::Data => {
+                self.data.push(out);
+                self.checksum = self.checksum.wrapping_add(out as u16);
+                if self.data.len() == self.length as usize {
+                    State::ChecksumLow
+                } else {
+                    State::Data
+                }
+            }
+            State::ChecksumLow => {
+                self.received_checksum = out as u16;
+                State::ChecksumHigh
+            }
+            State::ChecksumHigh => {
+                self.received_checksum |= (out as u16) << 8;
+                State::Alive
+            }
+            State::Alive => {
+                reply = 0x81;
+                self.handle_packet();
+                State::Status
+            }
+            State::Status => {
+                reply = self.status;
+                State::Magic1
+            }
+        };
+        Some(reply)
+    }
+
+    /// The printer never drives the clock
+    fn poll_transfer(&mut self, _out: u8) -> Option<u8> {
+        None
+    }
+}
+
+/// Expands the printer's run length encoding. A control byte with bit 7 set
+/// repeats the next byte (n & 0x7F) + 2 times, otherwise n + 1 literal bytes follow.
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(PACKET_BYTES);
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 != 0 {
+            let count = (control & 0x7F) as usize + 2;
+            if let Some(&v) = data.get(i) {
+                out.extend(std::iter::repeat_n(v, count));
+            }
+            i += 1;
+        } else {
+            let end = (i + control as usize + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    out
+}
+
+/// Turns tile data laid out 20 tiles per row into lines of shades 0-3
+fn render(data: &[u8], palette: u8) -> Vec<[u8; PRINT_WIDTH]> {
+    let tile_rows = data.len() / TILE_ROW_BYTES;
+    let mut lines = vec![[0; PRINT_WIDTH]; tile_rows * 8];
+    for (y, line) in lines.iter_mut().enumerate() {
+        for (x, pixel) in line.iter_mut().enumerate() {
+            let tile = (y / 8) * 20 + x / 8;
+            let adr = tile * 16 + (y % 8) * 2;
+            let bit = 7 - (x % 8);
+            let colour = ((data[adr + 1] >> bit) & 1) << 1 | ((data[adr] >> bit) & 1);
+            *pixel = (palette >> (colour * 2)) & 0b11;
+        }
+    }
+    lines
+}
+
+fn save_print(lines: &[[u8; PRINT_WIDTH]], path: &Path) -> Result<()> {
+    fs::create_dir_all(path.parent().unwrap_or_else(|| Path::new(".")))?;
+    let file = BufWriter::new(fs::File::create(path)?);
+    let mut encoder = png::Encoder::new(file, PRINT_WIDTH as u32, lines.len() as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let pixels: Vec<u8> = lines
+        .iter()
+        .flat_map(|line| line.iter())
+        .map(|&shade| 255 - shade * 85)
+        .collect();
+    encoder.write_header()?.write_image_data(&pixels)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_packet(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> (u8, u8) {
+        let mut packet = vec![
+            command,
+            compressed as u8,
+            data.len() as u8,
+            (data.len() >> 8) as u8,
+        ];
+        packet.extend_from_slice(data);
+        let checksum = packet
+            .iter()
+            .fold(0u16, |sum, &v| sum.wrapping_add(v as u16));
+        packet.insert(0, 0x33);
+        packet.insert(0, 0x88);
+        packet.push(checksum as u8);
+        packet.push((checksum >> 8) as u8);
+        for v in packet {
+            assert_eq!(printer.transfer(v), Some(0x00));
+        }
+        (printer.transfer(0).unwrap(), printer.transfer(0).unwrap())
+    }
+
+    #[test]
+    fn test_decompress() {
+        assert_eq!(
+            decompress(&[0x81, 0xAA, 0x01, 1, 2]),
+            vec![0xAA, 0xAA, 0xAA, 1, 2]
+        );
+    }
+
+    #[test]
+    fn test_printer_packets() {
+        let dir =
+            std::env::temp_dir().join(format!("chipsand_test_printer_{}", std::process::id()));
+        let mut printer = Printer::new(&dir);
+        assert_eq!(send_packet(&mut printer, 0x01, false, &[]), (0x81, 0x00));
+        let tiles = vec![0xFF; PACKET_BYTES];
+        assert_eq!(
+            send_packet(&mut printer, 0x04, false, &tiles),
+            (0x81, STATUS_UNPROCESSED)
+        );
+        // the same two tile rows, compressed
+        assert_eq!(
+            send_packet(
+                &mut printer,
+                0x04,
+                true,
+                &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFA, 0xFF]
+            ),
+            (0x81, STATUS_UNPROCESSED)
+        );
+        assert_eq!(printer.buffer.len(), 2 * PACKET_BYTES);
+
+        let (_, status) = send_packet(&mut printer, 0x02, false, &[1, 0x00, 0xE4, 0x40]);
+        assert_eq!(status, STATUS_PRINTING);
+        assert_eq!(printer.paper.len(), 32);
+        assert!(printer
+            .paper
+            .iter()
+            .all(|line| line.iter().all(|&v| v == 3)));
+        for _ in 0..PRINT_POLLS {
+            send_packet(&mut printer, 0x0F, false, &[]);
+        }
+        assert_eq!(send_packet(&mut printer, 0x0F, false, &[]), (0x81, 0x00));
+
+        for &v in &[0x88, 0x33, 0x0F, 0, 0, 0, 0, 0] {
+            printer.transfer(v);
+        }
+        assert_eq!(printer.transfer(0), Some(0x81));
+        assert_eq!(printer.transfer(0), Some(STATUS_CHECKSUM_ERROR));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
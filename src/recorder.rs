@@ -0,0 +1,191 @@
+use std::fs;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use crate::palette::{Palette, LAYER_OBJ1};
+use crate::ScreenBuffer;
+
+// 70224 / 4194304 seconds per frame, as close as the u16 fraction of apng gets
+const FRAME_DELAY: (u16, u16) = (100, 5973);
+
+enum Format {
+    /// Frames are written as they come and the frame count in the acTL chunk
+    /// at `actl` follows along, so a recording that is cut short still only
+    /// announces the frames it has. The second file is a handle for doing so.
+    Apng {
+        writer: png::Writer<fs::File>,
+        file: fs::File,
+        actl: u64,
+        frames: u32,
+    },
+    /// rgb24 frames streamed straight to the file
+    Raw(BufWriter<fs::File>),
+}
+
+/// Records every frame losslessly, as an animated png or as raw frames. Raw
+/// frames can be converted with
+/// `ffmpeg -f rawvideo -pixel_format rgb24 -video_size 160x144 -framerate 59.7275 -i <file> out.mp4`
+pub struct Recorder {
+    palette: Palette,
+    format: Format,
+}
+
+impl Recorder {
+    /// The format follows the extension, `.png` or `.apng` for an animated png
+    /// and `.rgb` or `.raw` for raw frames
+    pub fn create(path: &Path, palette: Palette) -> Result<Self> {
+        let format = match path.extension().and_then(|e| e.to_str()) {
+            Some("png") | Some("apng") => {
+                // read back too, to find the acTL chunk
+                let file = fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(path)?;
+                let mut handle = file.try_clone()?;
+                let writer = apng_writer(file, &palette)?;
+                Format::Apng {
+                    actl: find_actl(&mut handle)?,
+                    file: handle,
+                    writer,
+                    frames: 0,
+                }
+            }
+            Some("rgb") | Some("raw") => Format::Raw(BufWriter::new(fs::File::create(path)?)),
+            _ => bail!("record to a .png, .apng, .rgb or .raw file"),
+        };
+        Ok(Recorder { palette, format })
+    }
+
+    pub fn add_frame(&mut self, screen: &ScreenBuffer) -> Result<()> {
+        match &mut self.format {
+            Format::Apng {
+                writer,
+                file,
+                actl,
+                frames,
+            } => {
+                let packed: Vec<u8> = screen
+                    .iter()
+                    .flat_map(|row| row.chunks(2))
+                    .map(|p| p[0] << 4 | p[1])
+                    .collect();
+                writer.write_image_data(&packed)?;
+                *frames += 1;
+                set_frame_count(file, *actl, *frames)?;
+            }
+            Format::Raw(writer) => {
+                for &pixel in screen.iter().flat_map(|row| row.iter()) {
+                    let (r, g, b) = self.palette.colour(pixel);
+                    writer.write_all(&[r, g, b])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        match self.format {
+            Format::Apng { writer, frames, .. } => {
+                writer.finish()?;
+                if frames == 0 {
+                    bail!("no frames recorded");
+                }
+            }
+            Format::Raw(mut writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// Starts an animated png with more frames than any recording will have, the
+/// writer only checks the count to stop adding frame headers. The count in the
+/// file is kept up to date by `set_frame_count`.
+fn apng_writer(file: fs::File, palette: &Palette) -> Result<png::Writer<fs::File>> {
+    let mut encoder = png::Encoder::new(file, 160, 144);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Four);
+    // indexed by the screen buffer pixels, i.e. shade and layer
+    let colours: Vec<u8> = (0..=LAYER_OBJ1 | 3)
+        .flat_map(|pixel| {
+            let (r, g, b) = palette.colour(pixel);
+            vec![r, g, b]
+        })
+        .collect();
+    encoder.set_palette(colours);
+    encoder.set_animated(u32::MAX, 0)?;
+    encoder.set_frame_delay(FRAME_DELAY.0, FRAME_DELAY.1)?;
+    Ok(encoder.write_header()?)
+}
+
+/// Offset of the acTL chunk, the file position is left at the end
+fn find_actl(file: &mut fs::File) -> Result<u64> {
+    // chunks after the signature are length, type, data and crc
+    let mut offset = 8;
+    loop {
+        let mut header = [0; 8];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut header)?;
+        if &header[4..] == b"acTL" {
+            break;
+        }
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        offset += 12 + len as u64;
+    }
+    file.seek(SeekFrom::End(0))?;
+    Ok(offset)
+}
+
+/// Rewrites `num_frames` of the acTL chunk at `actl` and the chunk's crc. The
+/// writer shares the file position, so it is put back at the end.
+fn set_frame_count(file: &mut fs::File, actl: u64, frames: u32) -> Result<()> {
+    // num_plays stays 0, looping forever
+    let mut data = [0; 8];
+    data[..4].copy_from_slice(&frames.to_be_bytes());
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(b"acTL");
+    crc.update(&data);
+    file.seek(SeekFrom::Start(actl + 8))?;
+    file.write_all(&data)?;
+    file.write_all(&crc.finalize().to_be_bytes())?;
+    file.seek(SeekFrom::End(0))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::{GREY, LAYER_OBJ0};
+    use crate::BLANK_SCREEN;
+
+    #[test]
+    fn test_record_apng() {
+        let path = std::env::temp_dir().join(format!(
+            "chipsand_test_record_apng_{}.png",
+            std::process::id()
+        ));
+        let mut recorder = Recorder::create(&path, GREY).unwrap();
+        let mut screen = BLANK_SCREEN;
+        recorder.add_frame(&screen).unwrap();
+        screen[10][3] = LAYER_OBJ0 | 2;
+        recorder.add_frame(&screen).unwrap();
+
+        // announces what is there even before recording stops
+        let decoder = png::Decoder::new(fs::File::open(&path).unwrap());
+        let reader = decoder.read_info().unwrap();
+        assert_eq!(reader.info().animation_control().unwrap().num_frames, 2);
+        recorder.finish().unwrap();
+
+        let decoder = png::Decoder::new(fs::File::open(&path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        assert_eq!(reader.info().animation_control().unwrap().num_frames, 2);
+        let mut frame = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut frame).unwrap();
+        reader.next_frame(&mut frame).unwrap();
+        assert_eq!(frame[10 * 80 + 1], 0b0000_0110);
+        fs::remove_file(&path).unwrap();
+    }
+}
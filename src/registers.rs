@@ -1,3 +1,5 @@
+use serde::{Deserialize, Serialize};
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum R8 {
     A,
@@ -19,6 +21,7 @@ pub enum R16 {
     SP,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Registers {
     pub a: u8,
     pub b: u8,
@@ -0,0 +1,181 @@
+use std::sync::mpsc::{self, Receiver, SyncSender};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::cpu::CPU;
+
+/// Everything needed to continue where the game was saved, apart from
+/// the rom itself
+#[derive(Serialize, Deserialize)]
+struct SaveState {
+    title: String,
+    /// Cartridge ram and banking, see `MBC::save_state`
+    cartridge: Vec<u8>,
+    cpu: CPU,
+}
+
+impl CPU {
+    /// The emulated hardware as json
+    pub fn save_state(&self) -> Result<Vec<u8>> {
+        #[derive(Serialize)]
+        struct SaveStateRef<'a> {
+            title: String,
+            cartridge: Vec<u8>,
+            cpu: &'a CPU,
+        }
+        let state = SaveStateRef {
+            title: self.mmu.title(),
+            cartridge: self.mmu.cartridge_state(),
+            cpu: self,
+        };
+        Ok(serde_json::to_vec(&state)?)
+    }
+
+    /// Restores a state written by `save_state` for the same game. The cartridge
+    /// rom, cheats, link cable and frontend channels are kept as they are.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<()> {
+        let state: SaveState = serde_json::from_slice(data)?;
+        if state.title != self.mmu.title() {
+            bail!("the state is of '{}', not of this game", state.title);
+        }
+        let mut cpu = state.cpu;
+        cpu.mmu.keep_connections(&mut self.mmu);
+        cpu.mmu.load_cartridge_state(&state.cartridge)?;
+        *self = cpu;
+        Ok(())
+    }
+}
+
+/// A sender nothing listens to, taking the place of the frontend's until
+/// that is moved over
+pub(crate) fn unconnected_sender<T>() -> SyncSender<T> {
+    mpsc::sync_channel(0).0
+}
+
+pub(crate) fn unconnected_receiver<T>() -> Receiver<T> {
+    mpsc::channel().1
+}
+
+/// Serializes fixed size arrays of any length as bytes, boxed or not
+pub(crate) mod bytes {
+    use std::borrow::Borrow;
+    use std::convert::TryInto;
+
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer, B: Borrow<[u8; N]>, const N: usize>(
+        bytes: &B,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(bytes.borrow())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, B: From<[u8; N]>, const N: usize>(
+        deserializer: D,
+    ) -> Result<B, D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        let len = bytes.len();
+        let array: [u8; N] = bytes
+            .try_into()
+            .map_err(|_| D::Error::invalid_length(len, &"the array length"))?;
+        Ok(B::from(array))
+    }
+}
+
+/// Serializes a boxed `ScreenBuffer` as its pixels, row by row
+pub(crate) mod screen {
+    use std::borrow::Borrow;
+
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::{screen_buffer_to_vec, ScreenBuffer, BLANK_SCREEN};
+
+    pub fn serialize<S: Serializer, B: Borrow<ScreenBuffer>>(
+        screen: &B,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&screen_buffer_to_vec(screen.borrow()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Box<ScreenBuffer>, D::Error> {
+        let pixels = Vec::<u8>::deserialize(deserializer)?;
+        if pixels.len() != 160 * 144 {
+            return Err(D::Error::invalid_length(pixels.len(), &"160x144 pixels"));
+        }
+        let mut screen = Box::new(BLANK_SCREEN);
+        for (row, line) in screen.iter_mut().zip(pixels.chunks(160)) {
+            row.copy_from_slice(line);
+        }
+        Ok(screen)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cheats::Cheat;
+    use crate::mmu::MMU;
+
+    fn mock_cpu(rom: Vec<u8>) -> CPU {
+        let mut cpu = CPU::new(MMU::new(rom, unconnected_sender(), unconnected_receiver()));
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x138].copy_from_slice(b"GAME");
+        let mut cpu = mock_cpu(rom.clone());
+        cpu.mmu.write_word(0xC000, 0x42);
+        cpu.mmu.write_word(0xA000, 0x24);
+        cpu.regs.pc = 0x1234;
+        let state = cpu.save_state().unwrap();
+
+        cpu.mmu.write_word(0xC000, 0);
+        cpu.mmu.write_word(0xA000, 0);
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.regs.pc, 0x1234);
+        assert_eq!(cpu.mmu.read_word(0xC000), 0x42);
+        assert_eq!(cpu.mmu.read_word(0xA000), 0x24);
+        assert_eq!(cpu.mmu.read_word(0x0134), b'G');
+
+        rom[0x134..0x138].copy_from_slice(b"GOLF");
+        assert!(mock_cpu(rom).load_state(&state).is_err());
+    }
+
+    #[test]
+    fn test_cheats_survive_loading_a_state() {
+        let mut cpu = mock_cpu(vec![0; 0x8000]);
+        let state = cpu.save_state().unwrap();
+        cpu.mmu
+            .cheats
+            .add(Cheat {
+                code: "014200C0".to_string(),
+                name: String::new(),
+                enabled: true,
+            })
+            .unwrap();
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.mmu.cheats.list().count(), 1);
+        cpu.power_cycle();
+        assert_eq!(cpu.mmu.cheats.list().count(), 1);
+    }
+
+    #[test]
+    fn test_power_cycle_keeps_cartridge_ram() {
+        let mut cpu = mock_cpu(vec![0; 0x8000]);
+        cpu.mmu.write_word(0xC000, 0x42);
+        cpu.mmu.write_word(0xA000, 0x24);
+        cpu.regs.pc = 0x1234;
+        cpu.power_cycle();
+        assert_eq!(cpu.regs.pc, 0x0100);
+        assert_eq!(cpu.mmu.read_word(0xC000), 0);
+        assert_eq!(cpu.mmu.read_word(0xA000), 0x24);
+    }
+}
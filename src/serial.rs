@@ -1,5 +1,16 @@
-use crate::Interrupt;
+use std::mem;
 
+use serde::{Deserialize, Serialize};
+
+use crate::{Interrupt, FRAME_CYCLES};
+
+// Timer ticks in the 8192Hz bit period of the internal clock
+const BIT_TICKS: u16 = 128;
+// How long the clock master waits for an answer before shifting in 0xFF, in
+// emulated time so a silent peer slows the transfer but never the emulator
+const REPLY_TIMEOUT_TICKS: u32 = 2 * FRAME_CYCLES / 4;
+
+#[derive(Serialize, Deserialize)]
 pub struct SC {
     sio_clk: bool,
     sio_en: bool,
@@ -23,11 +34,50 @@ impl SC {
     }
 }
 
+/// The other end of a link cable. Transfers are exchanged a byte at a time,
+/// the side driving the clock starts them and the other side answers.
+pub trait SerialPeer: Send {
+    /// Sends `out` as clock master. Returns the byte shifted in from the peer
+    /// when it answers right away, otherwise `reply` is asked until it has.
+    fn transfer(&mut self, out: u8) -> Option<u8>;
+    /// The answer to the last `transfer`, once it has arrived. Never blocks.
+    fn reply(&mut self) -> Option<u8> {
+        None
+    }
+    /// Returns the byte of a transfer started by the peer, answering with `out`
+    fn poll_transfer(&mut self, out: u8) -> Option<u8>;
+}
+
+/// No cable attached, the data line floats high
+pub struct Disconnected;
+
+impl SerialPeer for Disconnected {
+    fn transfer(&mut self, _out: u8) -> Option<u8> {
+        Some(0xFF)
+    }
+
+    fn poll_transfer(&mut self, _out: u8) -> Option<u8> {
+        None
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Serial {
     pub sb: u8,
     sc: SC,
     counter: u16,
     sent: u8,
+    incoming: u8,
+    /// Ticks the current transfer has been waiting for the peer's answer
+    waited: u32,
+    #[serde(skip, default = "disconnected")]
+    peer: Box<dyn SerialPeer>,
+    #[serde(skip)]
+    output: Option<Vec<u8>>,
+}
+
+fn disconnected() -> Box<dyn SerialPeer> {
+    Box::new(Disconnected)
 }
 
 impl Serial {
@@ -37,9 +87,33 @@ impl Serial {
             sc: SC::new(),
             counter: 0,
             sent: 0,
+            incoming: 0xFF,
+            waited: 0,
+            peer: Box::new(Disconnected),
+            output: None,
         }
     }
 
+    /// Starts recording every byte shifted out, test roms report their results this way
+    pub fn capture_output(&mut self) {
+        self.output.get_or_insert_with(Vec::new);
+    }
+
+    /// Bytes shifted out since the last call
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.output.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    pub fn connect(&mut self, peer: Box<dyn SerialPeer>) {
+        self.peer = peer;
+    }
+
+    /// Takes over the cable and captured output of `old`
+    pub(crate) fn keep_connections(&mut self, old: &mut Serial) {
+        mem::swap(&mut self.peer, &mut old.peer);
+        mem::swap(&mut self.output, &mut old.output);
+    }
+
     pub fn read_word(&self, adr: u16) -> u8 {
         match adr {
             0xFF01 => self.sb,
@@ -51,29 +125,116 @@ impl Serial {
     pub fn write_word(&mut self, adr: u16, v: u8) {
         match adr {
             0xFF01 => self.sb = v,
-            0xFF02 => self.sc.write_word(v),
+            0xFF02 => {
+                self.sc.write_word(v);
+                self.counter = 0;
+                self.waited = 0;
+            }
             _ => panic!("Wrong write adr for Serial"),
         }
     }
 
     pub fn tick(&mut self) -> Interrupt {
-        if self.sc.sio_en {
-            // TODO: implement support for serial
-            // this is only provides a channel for writing
-            if self.sc.sio_clk {
-                self.counter += 1;
-                if self.counter % 128 == 0 {
-                    self.sent = (self.sent << 1) | (self.sb >> 7);
-                    self.sb = (self.sb << 1) & 0xFF;
-                    if self.counter == 8 * 128 {
-                        self.counter = 0;
-                        self.sent = 0;
-                        self.sc.sio_en = false;
-                        return Interrupt::SERIAL;
-                    }
+        // until the game arms a transfer, a byte the peer offers stays queued
+        if !self.sc.sio_en {
+            return Interrupt::NoInterrupt;
+        }
+        if !self.sc.sio_clk {
+            // external clock, the peer shifts the whole byte in at once.
+            // Polling once per bit period is plenty and keeps socket peers cheap
+            self.counter = (self.counter + 1) % BIT_TICKS;
+            if self.counter != 0 {
+                return Interrupt::NoInterrupt;
+            }
+            if let Some(v) = self.peer.poll_transfer(self.sb) {
+                self.sb = v;
+                self.sc.sio_en = false;
+                return Interrupt::SERIAL;
+            }
+            return Interrupt::NoInterrupt;
+        }
+        // shifting starts once the peer has answered
+        if self.counter == 0 && !self.has_reply() {
+            return Interrupt::NoInterrupt;
+        }
+        self.counter += 1;
+        if self.counter % BIT_TICKS == 0 {
+            let bit = 8 - self.counter / BIT_TICKS;
+            self.sent = (self.sent << 1) | (self.sb >> 7);
+            self.sb = (self.sb << 1) | ((self.incoming >> bit) & 1);
+            if self.counter == 8 * BIT_TICKS {
+                if let Some(output) = self.output.as_mut() {
+                    output.push(self.sent);
                 }
+                self.counter = 0;
+                self.sent = 0;
+                self.waited = 0;
+                self.sc.sio_en = false;
+                return Interrupt::SERIAL;
             }
         }
         Interrupt::NoInterrupt
     }
+
+    /// Asks the peer for its answer to the byte in `sb`, once per bit period.
+    /// A peer that stays silent for `REPLY_TIMEOUT_TICKS` reads as 0xFF.
+    fn has_reply(&mut self) -> bool {
+        let reply = if self.waited == 0 {
+            self.peer.transfer(self.sb)
+        } else if self.waited >= REPLY_TIMEOUT_TICKS {
+            Some(0xFF)
+        } else if self.waited & (BIT_TICKS as u32 - 1) == 0 {
+            self.peer.reply()
+        } else {
+            None
+        };
+        match reply {
+            Some(v) => {
+                self.incoming = v;
+                true
+            }
+            None => {
+                self.waited += 1;
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::ChannelPeer;
+
+    /// Ticks until the transfer completes, at most `limit` times
+    fn run(serial: &mut Serial, limit: u32) -> Option<u32> {
+        (1..=limit).find(|_| serial.tick() == Interrupt::SERIAL)
+    }
+
+    #[test]
+    fn test_unarmed_slave_keeps_the_byte_queued() {
+        let (mut master, slave) = ChannelPeer::pair();
+        let mut serial = Serial::new();
+        serial.connect(Box::new(slave));
+        assert_eq!(master.transfer(0x42), None);
+        assert_eq!(run(&mut serial, 10 * BIT_TICKS as u32), None);
+
+        serial.write_word(0xFF01, 0x24);
+        serial.write_word(0xFF02, 0x80);
+        assert!(run(&mut serial, BIT_TICKS as u32).is_some());
+        assert_eq!(serial.sb, 0x42);
+        assert_eq!(master.reply(), Some(0x24));
+    }
+
+    #[test]
+    fn test_silent_peer_reads_as_ff() {
+        let (master, _slave) = ChannelPeer::pair();
+        let mut serial = Serial::new();
+        serial.connect(Box::new(master));
+        serial.write_word(0xFF01, 0x42);
+        serial.write_word(0xFF02, 0x81);
+        let ticks = run(&mut serial, REPLY_TIMEOUT_TICKS + 8 * BIT_TICKS as u32 + 1);
+        assert_eq!(ticks, Some(REPLY_TIMEOUT_TICKS + 8 * BIT_TICKS as u32));
+        assert_eq!(serial.sb, 0xFF);
+    }
 }
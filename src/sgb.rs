@@ -0,0 +1,488 @@
+use std::sync::mpsc::Sender;
+
+use serde::{Deserialize, Serialize};
+
+use crate::palette::{rgb, Colour};
+use crate::savestate;
+use crate::ScreenBuffer;
+
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+// Where the Game Boy screen sits within the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+const CELLS_X: usize = 20;
+const CELLS_Y: usize = 18;
+const PACKET_BITS: usize = 16 * 8;
+const TRANSFER_SIZE: usize = 0x1000;
+const ATTR_FILE_SIZE: usize = 90;
+
+/// The Game Boy screen inside the border, as rgb colours row by row
+pub type SgbFrame = Vec<Colour>;
+
+// Palette 1-A, shown until the game sets its own colours
+const DEFAULT_COLOURS: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum Mask {
+    None,
+    Freeze,
+    Black,
+    Colour0,
+}
+
+/// Data sent by drawing it on screen, picked up with the next frame
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum Transfer {
+    Palettes,
+    Tiles(usize),
+    Border,
+    Attributes,
+}
+
+/// Super Game Boy: receives command packets pulsed through P14/P15 and
+/// colours the screen, framed by the border, with the palettes they set.
+#[derive(Serialize, Deserialize)]
+pub struct Sgb {
+    lines: u8,
+    bits: Option<usize>,
+    packet: [u8; 16],
+    command: Vec<u8>,
+    /// Colours 0-3 of the four screen palettes, as 15 bit bgr
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<u16>,
+    /// Palette of every 8x8 cell of the screen
+    attributes: [[u8; CELLS_X]; CELLS_Y],
+    attribute_files: Vec<u8>,
+    mask: Mask,
+    #[serde(with = "savestate::screen")]
+    frozen: Box<ScreenBuffer>,
+    transfer: Option<Transfer>,
+    /// 256 tiles at 4 bits per pixel in SNES layout
+    border_tiles: Vec<u8>,
+    /// 32x28 tile map followed by palettes 4-7
+    border_map: Vec<u8>,
+    /// Joypads requested with MLT_REQ: 1, 2 or 4
+    pub players: usize,
+    /// Composed frames are sent here when set
+    #[serde(skip)]
+    pub frame_sender: Option<Sender<SgbFrame>>,
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Sgb {
+            lines: 0b11,
+            bits: None,
+            packet: [0; 16],
+            command: Vec::new(),
+            palettes: [DEFAULT_COLOURS; 4],
+            system_palettes: vec![0; 512 * 4],
+            attributes: [[0; CELLS_X]; CELLS_Y],
+            attribute_files: vec![0; TRANSFER_SIZE],
+            mask: Mask::None,
+            frozen: Box::new([[0; 160]; 144]),
+            transfer: None,
+            border_tiles: vec![0; 2 * TRANSFER_SIZE],
+            border_map: vec![0; TRANSFER_SIZE],
+            players: 1,
+            frame_sender: None,
+        }
+    }
+
+    /// Follows writes to P1. Both lines low starts a packet, after that every
+    /// pulse of P14 low sends a 0 and of P15 low a 1, least significant bit first.
+    pub fn write_joypad(&mut self, v: u8) {
+        let lines = (v >> 4) & 0b11;
+        let previous = self.lines;
+        self.lines = lines;
+        if lines == 0b00 {
+            self.bits = Some(0);
+            self.packet = [0; 16];
+            return;
+        }
+        // bits are taken when a line is pulled low, not on the release
+        if previous != 0b11 || lines == 0b11 {
+            return;
+        }
+        let bits = match self.bits {
+            Some(bits) => bits,
+            None => return,
+        };
+        if bits == PACKET_BITS {
+            // the stop bit, always a 0
+            self.bits = None;
+            self.packet_received();
+            return;
+        }
+        if lines == 0b01 {
+            self.packet[bits / 8] |= 1 << (bits % 8);
+        }
+        self.bits = Some(bits + 1);
+    }
+
+    fn packet_received(&mut self) {
+        self.command.extend_from_slice(&self.packet);
+        let length = (self.command[0] & 0b111).max(1) as usize;
+        if self.command.len() >= length * 16 {
+            let command = std::mem::take(&mut self.command);
+            self.execute(&command);
+        }
+    }
+
+    fn execute(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            0x00 => self.set_palette_pair(0, 1, data),
+            0x01 => self.set_palette_pair(2, 3, data),
+            0x02 => self.set_palette_pair(0, 3, data),
+            0x03 => self.set_palette_pair(1, 2, data),
+            0x04 => self.attr_blk(data),
+            0x05 => self.attr_lin(data),
+            0x06 => self.attr_div(data),
+            0x07 => self.attr_chr(data),
+            0x0A => self.pal_set(data),
+            0x0B => self.transfer = Some(Transfer::Palettes),
+            0x11 => {
+                self.players = match data[1] & 0b11 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                }
+            }
+            0x13 => self.transfer = Some(Transfer::Tiles((data[1] & 1) as usize)),
+            0x14 => self.transfer = Some(Transfer::Border),
+            0x15 => self.transfer = Some(Transfer::Attributes),
+            0x16 => self.attr_set(data[1]),
+            0x17 => {
+                self.mask = match data[1] & 0b11 {
+                    0 => Mask::None,
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    _ => Mask::Colour0,
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Colour 0 is shared by all palettes, followed by colours 1-3 of each
+    fn set_palette_pair(&mut self, a: usize, b: usize, data: &[u8]) {
+        let colour = |i: usize| u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]);
+        for palette in self.palettes.iter_mut() {
+            palette[0] = colour(0);
+        }
+        for i in 1..4 {
+            self.palettes[a][i] = colour(i);
+            self.palettes[b][i] = colour(i + 3);
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[u8]) {
+        let count = (data[1] as usize).min(18);
+        for set in data[2..].chunks(6).take(count) {
+            if set.len() < 6 {
+                break;
+            }
+            let (inside, mut border, outside) = (set[0] & 1 != 0, set[0] & 2 != 0, set[0] & 4 != 0);
+            let (pal_in, mut pal_border, pal_out) =
+                (set[1] & 3, (set[1] >> 2) & 3, (set[1] >> 4) & 3);
+            // a lone inside or outside change takes the border along
+            if inside && !border && !outside {
+                border = true;
+                pal_border = pal_in;
+            } else if outside && !border && !inside {
+                border = true;
+                pal_border = pal_out;
+            }
+            let (x1, y1, x2, y2) = (
+                set[2] as usize,
+                set[3] as usize,
+                set[4] as usize,
+                set[5] as usize,
+            );
+            for (y, row) in self.attributes.iter_mut().enumerate() {
+                for (x, cell) in row.iter_mut().enumerate() {
+                    let within = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let on_edge = within && (x == x1 || x == x2 || y == y1 || y == y2);
+                    if on_edge {
+                        if border {
+                            *cell = pal_border;
+                        }
+                    } else if within {
+                        if inside {
+                            *cell = pal_in;
+                        }
+                    } else if outside {
+                        *cell = pal_out;
+                    }
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for &line in data[2..].iter().take(count) {
+            let (n, palette) = ((line & 0x1F) as usize, (line >> 5) & 3);
+            if line & 0x80 != 0 {
+                // a vertical line is a column
+                for row in self.attributes.iter_mut() {
+                    if let Some(cell) = row.get_mut(n) {
+                        *cell = palette;
+                    }
+                }
+            } else if let Some(row) = self.attributes.get_mut(n) {
+                *row = [palette; CELLS_X];
+            }
+        }
+    }
+
+    fn attr_div(&mut self, data: &[u8]) {
+        let (after, before, on) = (data[1] & 3, (data[1] >> 2) & 3, (data[1] >> 4) & 3);
+        let horizontal = data[1] & 0x40 != 0;
+        let line = data[2] as usize;
+        for (y, row) in self.attributes.iter_mut().enumerate() {
+            for (x, cell) in row.iter_mut().enumerate() {
+                let position = if horizontal { y } else { x };
+                *cell = match position.cmp(&line) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+
+    fn attr_chr(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+        let count = (u16::from_le_bytes([data[3], data[4]]) as usize).min(CELLS_X * CELLS_Y);
+        let vertical = data[5] & 1 != 0;
+        for i in 0..count {
+            let byte = match data.get(6 + i / 4) {
+                Some(&byte) => byte,
+                None => break,
+            };
+            if x >= CELLS_X || y >= CELLS_Y {
+                break;
+            }
+            self.attributes[y][x] = (byte >> (6 - (i % 4) * 2)) & 3;
+            if vertical {
+                y += 1;
+                if y == CELLS_Y {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == CELLS_X {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    fn pal_set(&mut self, data: &[u8]) {
+        for (i, palette) in self.palettes.iter_mut().enumerate() {
+            let id = (u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]) & 0x1FF) as usize;
+            palette.copy_from_slice(&self.system_palettes[id * 4..id * 4 + 4]);
+        }
+        // colour 0 of the first palette is the one shared by all
+        let colour0 = self.palettes[0][0];
+        for palette in self.palettes.iter_mut() {
+            palette[0] = colour0;
+        }
+        if data[9] & 0x80 != 0 {
+            self.attr_set(data[9]);
+        }
+        if data[9] & 0x40 != 0 {
+            self.mask = Mask::None;
+        }
+    }
+
+    fn attr_set(&mut self, v: u8) {
+        let file = (v & 0x3F) as usize;
+        if file < 45 {
+            let data = &self.attribute_files[file * ATTR_FILE_SIZE..(file + 1) * ATTR_FILE_SIZE];
+            for (i, cell) in self.attributes.iter_mut().flatten().enumerate() {
+                *cell = (data[i / 4] >> (6 - (i % 4) * 2)) & 3;
+            }
+        }
+        if v & 0x40 != 0 {
+            self.mask = Mask::None;
+        }
+    }
+
+    /// Takes a finished Game Boy frame, picking up transferred data, and
+    /// composes the bordered frame
+    pub fn frame(&mut self, screen: &ScreenBuffer) -> SgbFrame {
+        if let Some(transfer) = self.transfer.take() {
+            let data = transfer_data(screen);
+            match transfer {
+                Transfer::Palettes => {
+                    for (i, colour) in self.system_palettes.iter_mut().enumerate() {
+                        *colour = u16::from_le_bytes([data[i * 2], data[i * 2 + 1]]);
+                    }
+                }
+                Transfer::Tiles(half) => self.border_tiles
+                    [half * TRANSFER_SIZE..(half + 1) * TRANSFER_SIZE]
+                    .copy_from_slice(&data),
+                Transfer::Border => self.border_map.copy_from_slice(&data),
+                Transfer::Attributes => self.attribute_files.copy_from_slice(&data),
+            }
+        }
+        if self.mask != Mask::Freeze {
+            *self.frozen = *screen;
+        }
+        let backdrop = rgb(self.palettes[0][0]);
+        let mut frame = vec![backdrop; SGB_WIDTH * SGB_HEIGHT];
+        for (y, row) in self.frozen.iter().enumerate() {
+            for (x, &pixel) in row.iter().enumerate() {
+                let colour = match self.mask {
+                    Mask::Black => (0, 0, 0),
+                    Mask::Colour0 => backdrop,
+                    _ => {
+                        let palette = self.attributes[y / 8][x / 8] as usize;
+                        rgb(self.palettes[palette][(pixel & 0b11) as usize])
+                    }
+                };
+                frame[(SCREEN_Y + y) * SGB_WIDTH + SCREEN_X + x] = colour;
+            }
+        }
+        self.draw_border(&mut frame);
+        if let Some(sender) = &self.frame_sender {
+            let _ = sender.send(frame.clone());
+        }
+        frame
+    }
+
+    /// Colour 0 of the border is transparent and the screen stays on top
+    fn draw_border(&self, frame: &mut SgbFrame) {
+        let on_screen = |x: usize, y: usize| {
+            (SCREEN_X..SCREEN_X + 160).contains(&x) && (SCREEN_Y..SCREEN_Y + 144).contains(&y)
+        };
+        for ty in 0..SGB_HEIGHT / 8 {
+            for tx in 0..SGB_WIDTH / 8 {
+                let i = (ty * 32 + tx) * 2;
+                let entry = u16::from_le_bytes([self.border_map[i], self.border_map[i + 1]]);
+                let tile = &self.border_tiles[(entry & 0xFF) as usize * 32..][..32];
+                let palette = ((entry >> 10) & 0b111) as usize;
+                let (flip_x, flip_y) = (entry & 0x4000 != 0, entry & 0x8000 != 0);
+                for row in 0..8 {
+                    let planes = [
+                        tile[row * 2],
+                        tile[row * 2 + 1],
+                        tile[16 + row * 2],
+                        tile[17 + row * 2],
+                    ];
+                    for col in 0..8 {
+                        let bit = 7 - col;
+                        let index = planes
+                            .iter()
+                            .enumerate()
+                            .fold(0, |c, (p, &plane)| c | ((plane >> bit) & 1) << p);
+                        let x = tx * 8 + if flip_x { 7 - col } else { col };
+                        let y = ty * 8 + if flip_y { 7 - row } else { row };
+                        if index == 0 || on_screen(x, y) || palette < 4 {
+                            continue;
+                        }
+                        let offset = 0x800 + ((palette - 4) * 16 + index as usize) * 2;
+                        let colour = u16::from_le_bytes([
+                            self.border_map[offset],
+                            self.border_map[offset + 1],
+                        ]);
+                        frame[y * SGB_WIDTH + x] = rgb(colour);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The first 256 tiles shown, 20 to a row, read back as 2 bits per pixel
+/// tile data the way the SGB captures them off the screen
+fn transfer_data(screen: &ScreenBuffer) -> Vec<u8> {
+    let mut data = Vec::with_capacity(TRANSFER_SIZE);
+    for tile in 0..TRANSFER_SIZE / 16 {
+        let (tx, ty) = (tile % CELLS_X * 8, tile / CELLS_X * 8);
+        for row in &screen[ty..ty + 8] {
+            let (mut low, mut high) = (0, 0);
+            for (i, &pixel) in row[tx..tx + 8].iter().enumerate() {
+                low |= (pixel & 1) << (7 - i);
+                high |= ((pixel >> 1) & 1) << (7 - i);
+            }
+            data.push(low);
+            data.push(high);
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BLANK_SCREEN;
+
+    fn send_packet(sgb: &mut Sgb, packet: &[u8; 16]) {
+        sgb.write_joypad(0x00);
+        sgb.write_joypad(0x30);
+        for i in 0..PACKET_BITS {
+            let bit = (packet[i / 8] >> (i % 8)) & 1;
+            sgb.write_joypad(if bit == 1 { 0x10 } else { 0x20 });
+            sgb.write_joypad(0x30);
+        }
+        sgb.write_joypad(0x20);
+        sgb.write_joypad(0x30);
+    }
+
+    #[test]
+    fn test_pal01_and_attr_div() {
+        let mut sgb = Sgb::new();
+        let mut packet = [0; 16];
+        packet[0] = 1; // PAL01, one packet
+                       // colour 0 white, colour 3 of palette 1 pure red
+        packet[1..3].copy_from_slice(&0x7FFFu16.to_le_bytes());
+        packet[13..15].copy_from_slice(&0x001Fu16.to_le_bytes());
+        send_packet(&mut sgb, &packet);
+        assert_eq!(sgb.palettes[0][0], 0x7FFF);
+        assert_eq!(sgb.palettes[1][3], 0x001F);
+
+        // palette 1 right of column 10
+        let mut packet = [0; 16];
+        packet[0] = 0x06 << 3 | 1;
+        packet[1] = 0b00_00_01;
+        packet[2] = 10;
+        send_packet(&mut sgb, &packet);
+        let mut screen = BLANK_SCREEN;
+        screen[0][159] = 3;
+        let frame = sgb.frame(&screen);
+        assert_eq!(frame[SCREEN_Y * SGB_WIDTH + SCREEN_X], (255, 255, 255));
+        assert_eq!(frame[SCREEN_Y * SGB_WIDTH + SCREEN_X + 159], (255, 0, 0));
+    }
+
+    #[test]
+    fn test_mlt_req() {
+        let mut sgb = Sgb::new();
+        let mut packet = [0; 16];
+        packet[0] = 0x11 << 3 | 1;
+        packet[1] = 3;
+        send_packet(&mut sgb, &packet);
+        assert_eq!(sgb.players, 4);
+    }
+
+    #[test]
+    fn test_transfer_data_tiles() {
+        let mut screen = BLANK_SCREEN;
+        // second tile, first row: colour 3 then colour 1
+        screen[0][8] = 3;
+        screen[0][9] = 1;
+        let data = transfer_data(&screen);
+        assert_eq!(&data[16..18], &[0b1100_0000, 0b1000_0000]);
+    }
+}
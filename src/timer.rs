@@ -1,9 +1,22 @@
+use serde::{Deserialize, Serialize};
+
 use super::Interrupt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum TimaState {
+    Running,
+    // TIMA reads 0 for one cycle before TMA is loaded
+    Overflowed,
+    // the cycle TMA is copied, writes to TIMA are ignored and TMA writes go through
+    Reloading,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Timer {
     big_div: u16,
     delayed_edge: bool,
     tac: u8,
-    tima_reload: bool,
+    tima_state: TimaState,
     pub tima: u8,
     pub tma: u8,
     timer_enabled: bool,
@@ -12,24 +25,22 @@ pub struct Timer {
 impl Timer {
     pub fn new() -> Self {
         Timer {
-            big_div: 0xABCC,
+            big_div: 0,
             delayed_edge: false,
             tac: 0,
-            tima_reload: false,
+            tima_state: TimaState::Running,
             tima: 0,
             tma: 0,
             timer_enabled: false,
         }
     }
 
-    pub fn tick(&mut self) -> Interrupt {
-        let mut interrupt = Interrupt::NoInterrupt;
-        if self.tima_reload {
-            self.tima = self.tma;
-            self.tima_reload = false;
-            interrupt = Interrupt::TIMER;
-        }
-        self.big_div = self.big_div.wrapping_add(4);
+    pub fn set_internal_div(&mut self, v: u16) {
+        self.big_div = v;
+        self.delayed_edge = self.edge_signal();
+    }
+
+    fn edge_signal(&self) -> bool {
         let bit = match self.tac {
             0b00 => 9, // 1024
             0b11 => 7, // 256
@@ -37,20 +48,38 @@ impl Timer {
             0b01 => 3, // 16
             _ => panic!("selected impossible bit in timer counter"),
         };
-        let edge = self.big_div & 1 << bit != 0 && self.timer_enabled;
+        self.big_div & 1 << bit != 0 && self.timer_enabled
+    }
+
+    // Any change to DIV or TAC that drops the selected signal increments TIMA
+    fn detect_falling_edge(&mut self) {
+        let edge = self.edge_signal();
         if !edge && self.delayed_edge {
-            self.delayed_edge = edge;
             let (sum, carry) = self.tima.overflowing_add(1);
             if carry {
-                self.tima_reload = true;
+                self.tima_state = TimaState::Overflowed;
                 self.tima = 0;
             } else {
                 self.tima = sum;
             }
-        } else {
-            self.delayed_edge = edge;
         }
-        return interrupt;
+        self.delayed_edge = edge;
+    }
+
+    pub fn tick(&mut self) -> Interrupt {
+        let mut interrupt = Interrupt::NoInterrupt;
+        match self.tima_state {
+            TimaState::Overflowed => {
+                self.tima = self.tma;
+                self.tima_state = TimaState::Reloading;
+                interrupt = Interrupt::TIMER;
+            }
+            TimaState::Reloading => self.tima_state = TimaState::Running,
+            TimaState::Running => {}
+        }
+        self.big_div = self.big_div.wrapping_add(4);
+        self.detect_falling_edge();
+        interrupt
     }
 
     pub fn read_word(&self, adr: u16) -> u8 {
@@ -65,15 +94,28 @@ impl Timer {
 
     pub fn write_word(&mut self, adr: u16, v: u8) {
         match adr {
-            0xFF04 => self.big_div = 0,
-            0xFF05 => {
-                self.tima_reload = false;
-                self.tima = v
+            0xFF04 => {
+                self.big_div = 0;
+                self.detect_falling_edge();
+            }
+            0xFF05 => match self.tima_state {
+                TimaState::Reloading => {}
+                TimaState::Overflowed => {
+                    self.tima_state = TimaState::Running;
+                    self.tima = v
+                }
+                TimaState::Running => self.tima = v,
+            },
+            0xFF06 => {
+                self.tma = v;
+                if self.tima_state == TimaState::Reloading {
+                    self.tima = v;
+                }
             }
-            0xFF06 => self.tma = v,
             0xFF07 => {
                 self.tac = v & 0b11;
-                self.timer_enabled = (v & 0b100) != 0
+                self.timer_enabled = (v & 0b100) != 0;
+                self.detect_falling_edge();
             }
             _ => panic!("Timer wrong write adr"),
         }
@@ -94,4 +136,43 @@ mod tests {
         assert_eq!(timer.timer_enabled, false);
         timer.write_word(0xFF07, 0b111);
     }
+
+    #[test]
+    fn test_div_write_falling_edge() {
+        let mut timer = Timer::new();
+        timer.write_word(0xFF07, 0b101);
+        timer.set_internal_div(0b1000);
+        timer.write_word(0xFF04, 0);
+        assert_eq!(timer.tima, 1);
+    }
+
+    #[test]
+    fn test_tac_disable_falling_edge() {
+        let mut timer = Timer::new();
+        timer.write_word(0xFF07, 0b101);
+        timer.set_internal_div(0b1000);
+        timer.write_word(0xFF07, 0b001);
+        assert_eq!(timer.tima, 1);
+    }
+
+    #[test]
+    fn test_tima_reload_writes() {
+        let mut timer = Timer::new();
+        timer.write_word(0xFF07, 0b101);
+        timer.write_word(0xFF05, 0xFF);
+        timer.write_word(0xFF06, 0x10);
+        while timer.tima != 0 {
+            timer.tick();
+        }
+        assert_eq!(timer.read_word(0xFF05), 0);
+        assert_eq!(timer.tick(), Interrupt::TIMER);
+        assert_eq!(timer.tima, 0x10);
+        timer.write_word(0xFF05, 0x33);
+        assert_eq!(timer.tima, 0x10);
+        timer.write_word(0xFF06, 0x20);
+        assert_eq!(timer.tima, 0x20);
+        timer.tick();
+        timer.write_word(0xFF05, 0x33);
+        assert_eq!(timer.tima, 0x33);
+    }
 }
@@ -0,0 +1,234 @@
+use crate::palette::{Colour, Palette, LAYER_BG, LAYER_OBJ0, LAYER_OBJ1};
+use crate::ppu::{merge_bytes, tile_row_adr, PPU};
+
+const TILES: usize = 384;
+const TILES_PER_ROW: usize = 16;
+const MAP_SIZE: usize = 256;
+// Outline of the part of the background map on screen
+const VIEWPORT_COLOUR: Colour = (255, 0, 0);
+
+/// An rgb image, row by row
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Colour>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Self {
+        Image {
+            width,
+            height,
+            pixels: vec![(0, 0, 0); width * height],
+        }
+    }
+
+    /// Copies `image` in with its top left corner at `x`, `y`
+    pub fn blit(&mut self, image: &Image, x: usize, y: usize) {
+        for (row, line) in image.pixels.chunks(image.width).enumerate() {
+            let start = (y + row) * self.width + x;
+            self.pixels[start..start + image.width].copy_from_slice(line);
+        }
+    }
+
+    pub fn to_rgb(&self) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|&(r, g, b)| vec![r, g, b])
+            .collect()
+    }
+}
+
+/// A sprite as stored in OAM
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OamEntry {
+    pub index: usize,
+    /// Position of the top left corner on screen
+    pub x: i16,
+    pub y: i16,
+    pub tile: u8,
+    pub obp1: bool,
+    pub flip_x: bool,
+    pub flip_y: bool,
+    pub behind_bg: bool,
+}
+
+/// Copy of the ppu state the viewers need, taken once per frame so it can be
+/// looked at from another thread
+#[derive(Debug, Clone)]
+pub struct VramSnapshot {
+    pub vram: Vec<u8>,
+    pub oam: Vec<u8>,
+    pub lcdc: u8,
+    pub scx: u8,
+    pub scy: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+}
+
+impl VramSnapshot {
+    pub fn of(ppu: &PPU) -> Self {
+        VramSnapshot {
+            vram: ppu.vram.to_vec(),
+            oam: ppu.oam.to_vec(),
+            lcdc: ppu.read_word(0xFF40),
+            scx: ppu.scx,
+            scy: ppu.scy,
+            bgp: ppu.bgp,
+            obp0: ppu.obp0,
+            obp1: ppu.obp1,
+        }
+    }
+
+    /// Colour index of pixel `x`, `y` of the tile at vram offset `adr`
+    fn tile_pixel(&self, adr: usize, x: usize, y: usize) -> u8 {
+        let row = &self.vram[adr + y * 2..];
+        merge_bytes(row[0], row[1])[x]
+    }
+
+    /// All 384 tiles, 16 to a row, coloured through the palette register `reg`
+    pub fn tiles(&self, reg: u8, palette: &Palette) -> Image {
+        let mut image = Image::new(TILES_PER_ROW * 8, TILES / TILES_PER_ROW * 8);
+        for tile in 0..TILES {
+            let (tx, ty) = (tile % TILES_PER_ROW * 8, tile / TILES_PER_ROW * 8);
+            for y in 0..8 {
+                for x in 0..8 {
+                    let colour = self.tile_pixel(tile * 16, x, y);
+                    let shade = (reg >> (colour * 2)) & 0b11;
+                    image.pixels[(ty + y) * image.width + tx + x] =
+                        palette.colour(LAYER_BG | shade);
+                }
+            }
+        }
+        image
+    }
+
+    /// Background map 0 (0x9800) or 1 (0x9C00) with the tile addressing and
+    /// BGP in use. The map shown as background gets the scrolled screen outlined.
+    pub fn tile_map(&self, map: usize, palette: &Palette) -> Image {
+        let unsigned_adr = self.lcdc & 0b1_0000 != 0;
+        let base = 0x1800 + map * 0x400;
+        let mut image = Image::new(MAP_SIZE, MAP_SIZE);
+        for (i, pixel) in image.pixels.iter_mut().enumerate() {
+            let (x, y) = (i % MAP_SIZE, i / MAP_SIZE);
+            let tile = self.vram[base + y / 8 * 32 + x / 8];
+            let row = &self.vram[tile_row_adr(tile, unsigned_adr, (y % 8) as u8)..];
+            let colour = merge_bytes(row[0], row[1])[x % 8];
+            let shade = (self.bgp >> (colour * 2)) & 0b11;
+            *pixel = palette.colour(LAYER_BG | shade);
+        }
+        let bg_map = (self.lcdc >> 3) as usize & 1;
+        if map == bg_map {
+            let (scx, scy) = (self.scx as usize, self.scy as usize);
+            for dx in 0..160 {
+                for &dy in &[0, 143] {
+                    let (x, y) = ((scx + dx) % MAP_SIZE, (scy + dy) % MAP_SIZE);
+                    image.pixels[y * MAP_SIZE + x] = VIEWPORT_COLOUR;
+                }
+            }
+            for dy in 0..144 {
+                for &dx in &[0, 159] {
+                    let (x, y) = ((scx + dx) % MAP_SIZE, (scy + dy) % MAP_SIZE);
+                    image.pixels[y * MAP_SIZE + x] = VIEWPORT_COLOUR;
+                }
+            }
+        }
+        image
+    }
+
+    pub fn oam_entries(&self) -> Vec<OamEntry> {
+        self.oam
+            .chunks(4)
+            .enumerate()
+            .map(|(index, entry)| OamEntry {
+                index,
+                x: entry[1] as i16 - 8,
+                y: entry[0] as i16 - 16,
+                tile: entry[2],
+                obp1: entry[3] & 0x10 != 0,
+                flip_x: entry[3] & 0x20 != 0,
+                flip_y: entry[3] & 0x40 != 0,
+                behind_bg: entry[3] & 0x80 != 0,
+            })
+            .collect()
+    }
+
+    /// The 40 sprites as drawn, 8 to a row in 8x16 cells. Colour 0 is left
+    /// as colour 0 of their palette instead of transparent.
+    pub fn sprites(&self, palette: &Palette) -> Image {
+        let tall = self.lcdc & 0b100 != 0;
+        let height = if tall { 16 } else { 8 };
+        let mut image = Image::new(8 * 8, 5 * 16);
+        for entry in self.oam_entries() {
+            let (cx, cy) = (entry.index % 8 * 8, entry.index / 8 * 16);
+            let (reg, layer) = if entry.obp1 {
+                (self.obp1, LAYER_OBJ1)
+            } else {
+                (self.obp0, LAYER_OBJ0)
+            };
+            let tile = if tall { entry.tile & 0xFE } else { entry.tile } as usize;
+            for y in 0..height {
+                for x in 0..8 {
+                    let ty = if entry.flip_y { height - 1 - y } else { y };
+                    let tx = if entry.flip_x { 7 - x } else { x };
+                    let colour = self.tile_pixel(tile * 16 + ty / 8 * 16, tx, ty % 8);
+                    let shade = (reg >> (colour * 2)) & 0b11;
+                    image.pixels[(cy + y) * image.width + cx + x] = palette.colour(layer | shade);
+                }
+            }
+        }
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::GREY;
+
+    fn snapshot() -> VramSnapshot {
+        VramSnapshot {
+            vram: vec![0; 0x2000],
+            oam: vec![0; 0xA0],
+            lcdc: 0x91,
+            scx: 0,
+            scy: 0,
+            bgp: 0b11100100,
+            obp0: 0b11100100,
+            obp1: 0,
+        }
+    }
+
+    #[test]
+    fn test_tiles_and_map() {
+        let mut snapshot = snapshot();
+        // tile 1 row 0 leftmost pixel is colour 3
+        snapshot.vram[16] = 0x80;
+        snapshot.vram[17] = 0x80;
+        snapshot.vram[0x1800 + 1] = 1;
+        let tiles = snapshot.tiles(snapshot.bgp, &GREY);
+        assert_eq!((tiles.width, tiles.height), (128, 192));
+        assert_eq!(tiles.pixels[8], (0, 0, 0));
+
+        // the second map tile is tile 1, the top left corner outlines the screen
+        snapshot.scy = 8;
+        let map = snapshot.tile_map(0, &GREY);
+        assert_eq!(map.pixels[8], (0, 0, 0));
+        assert_eq!(map.pixels[8 * MAP_SIZE + 1], VIEWPORT_COLOUR);
+        assert!(!snapshot
+            .tile_map(1, &GREY)
+            .pixels
+            .contains(&VIEWPORT_COLOUR));
+    }
+
+    #[test]
+    fn test_oam_entries() {
+        let mut snapshot = snapshot();
+        snapshot.oam[4..8].copy_from_slice(&[16, 8, 0x42, 0b1011_0000]);
+        let entry = snapshot.oam_entries()[1];
+        assert_eq!((entry.x, entry.y, entry.tile), (0, 0, 0x42));
+        assert!(entry.obp1 && entry.flip_x && !entry.flip_y && entry.behind_bg);
+    }
+}
@@ -0,0 +1,48 @@
+use std::fs;
+use std::sync::mpsc;
+
+use chipsandlib::cpu::CPU;
+use chipsandlib::mmu::MMU;
+use chipsandlib::model::Model;
+use chipsandlib::registers::{RegIO, R8};
+use chipsandlib::CPU_CLOCK;
+
+// Mooneye roms finish well within this, anything longer is a hang
+const TIMEOUT_CYCLES: u64 = 20 * CPU_CLOCK as u64;
+
+/// Runs a mooneye rom until it hits the `LD B,B` breakpoint and checks
+/// B, C, D, E, H and L for the fibonacci signature of a passing test.
+fn mooneye_test(rom_path: &str, model: Model) {
+    let data = fs::read(rom_path).unwrap_or_else(|e| panic!("unable to open {}: {}", rom_path, e));
+    let (tx, rx) = mpsc::sync_channel(1);
+    let (_tx_events, rx_events) = mpsc::channel();
+    let mut mmu = MMU::new(data, tx, rx_events);
+    mmu.model = model;
+    let mut cpu = CPU::new(mmu);
+    cpu.reset();
+
+    while !cpu.breakpoint {
+        assert!(
+            cpu.cycles < TIMEOUT_CYCLES,
+            "{} timed out after {} cycles",
+            rom_path,
+            cpu.cycles
+        );
+        cpu.cycle();
+        while rx.try_recv().is_ok() {}
+    }
+
+    let signature: Vec<u8> = [R8::B, R8::C, R8::D, R8::E, R8::H, R8::L]
+        .iter()
+        .map(|&r| cpu.regs.read(r))
+        .collect();
+    assert_eq!(
+        signature,
+        vec![3, 5, 8, 13, 21, 34],
+        "{} failed after {} cycles",
+        rom_path,
+        cpu.cycles
+    );
+}
+
+include!(concat!(env!("OUT_DIR"), "/mooneye_tests.rs"));